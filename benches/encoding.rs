@@ -0,0 +1,44 @@
+//! Compares JSON and CBOR for a typical 15 second `Add` batch.
+//! Payload sizes are printed before the timings.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use monitor::http::{Add, Encoding, Interval};
+use monitor::{ActiveProgram, Program};
+
+fn sample_add() -> Add {
+    let mut add = Add::new(42);
+    let programs = [
+        ("Firefox", Some("youtube.com")),
+        ("Firefox", Some("docs.google.com")),
+        ("Firefox", None),
+        ("Visual Studio Code", Some("monitor-v3")),
+        ("Xfce Terminal", Some("cargo")),
+        ("Xfce Terminal", Some("vim")),
+        ("Discord", None),
+        ("Thunar", None),
+    ];
+    for (i, &(program, subprogram)) in programs.iter().enumerate() {
+        let active = ActiveProgram { program: program.to_owned(), subprogram: subprogram.map(str::to_owned) };
+        add.active.insert(active.clone(), 2);
+        add.open.insert(Program { program: program.to_owned() }, 15);
+        add.program_ids.insert(program.to_owned(), program.to_lowercase().replace(" ", "-"));
+        add.intervals.push(Interval { program: active, start: 1_600_000_000_000 + i as u64 * 2000, end: 1_600_000_002_000 + i as u64 * 2000 });
+    }
+    add.client = Some("17b5e0c1a2f-3039".to_owned());
+    add.seq = Some(1234);
+    add
+}
+
+fn bench_encoding(c: &mut Criterion) {
+    let add = sample_add();
+    for &encoding in [Encoding::Json, Encoding::Cbor].iter() {
+        let data = encoding.encode(&add).unwrap();
+        println!("{:?}: {} bytes", encoding, data.len());
+
+        c.bench_function(&format!("encode {:?}", encoding), |b| b.iter(|| encoding.encode(black_box(&add)).unwrap()));
+        c.bench_function(&format!("decode {:?}", encoding), |b| b.iter(|| encoding.decode::<Add>(black_box(&data)).unwrap()));
+    }
+}
+
+criterion_group!(benches, bench_encoding);
+criterion_main!(benches);
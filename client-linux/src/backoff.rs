@@ -0,0 +1,80 @@
+use std::time::{Duration, Instant};
+
+/// Exponential backoff with jitter for requests to the server.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+    retry_at: Option<Instant>,
+    /// xorshift state, for jitter
+    rng: u64,
+}
+
+impl Backoff {
+    /// Waits `base` after the first failure, doubling up to `max`.
+    pub fn new(base: Duration, max: Duration) -> Self {
+        let seed = monitor::http::unix_millis() ^ (u64::from(std::process::id()) << 32);
+        Backoff { base, max, failures: 0, retry_at: None, rng: seed | 1 }
+    }
+
+    /// Whether the last request succeeded.
+    pub fn online(&self) -> bool {
+        self.failures == 0
+    }
+
+    /// Whether it is time to try again.
+    pub fn ready(&self) -> bool {
+        match self.retry_at {
+            Some(at) => Instant::now() >= at,
+            None => true,
+        }
+    }
+
+    /// Records a success. Returns true if the server was unreachable before.
+    pub fn succeed(&mut self) -> bool {
+        let was_offline = !self.online();
+        self.failures = 0;
+        self.retry_at = None;
+        was_offline
+    }
+
+    /// Records a failure and returns how long to wait before trying again:
+    /// between half and all of `base * 2^failures`, at most `max`.
+    pub fn fail(&mut self) -> Duration {
+        let exp = self.base.checked_mul(1 << self.failures.min(16)).unwrap_or(self.max).min(self.max);
+        self.failures = self.failures.saturating_add(1);
+
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let half = exp / 2;
+        let delay = half + half.mul_f64((self.rng % 1000) as f64 / 1000.0);
+
+        self.retry_at = Some(Instant::now() + delay);
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays() {
+        let mut backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(60));
+        assert!(backoff.online() && backoff.ready());
+
+        for failures in 0..8 {
+            let delay = backoff.fail();
+            let exp = (Duration::from_secs(2) * (1 << failures)).min(Duration::from_secs(60));
+            assert!(delay >= exp / 2 && delay <= exp, "{:?} not within {:?}", delay, exp);
+        }
+        assert!(!backoff.online());
+        assert!(!backoff.ready());
+
+        assert!(backoff.succeed());
+        assert!(!backoff.succeed());
+        assert!(backoff.ready());
+        assert!(backoff.fail() <= Duration::from_secs(2));
+    }
+}
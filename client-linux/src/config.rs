@@ -0,0 +1,294 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use monitor::http::{DeviceID, DeviceType};
+use monitor::privacy::Privacy;
+use monitor::rules::RuleSet;
+
+pub const WINDOW_BACKENDS: &[&str] = &["auto", "x11", "sway"];
+pub const IDLE_BACKENDS: &[&str] = &["auto", "x11", "none"];
+
+/// `$XDG_CONFIG_HOME/monitor/client.toml`.
+pub fn default_path() -> PathBuf {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&std::env::var_os("HOME").unwrap_or_default()).join(".config"),
+    };
+    base.join("monitor/client.toml")
+}
+
+/// Rules or privacy settings, either written out in the config file or in a file of their own.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Section {
+    /// A `.toml` or `.json` file, relative to the config file.
+    File(PathBuf),
+    Inline(toml::Value),
+}
+
+/// How often things happen, in seconds.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Intervals {
+    /// Reading the windows. When window changes are watched this only checks for locking
+    /// and idling, and no more often than every `sample::WATCHED_TICK` seconds.
+    pub sample: u32,
+    /// Sending a batch to the server.
+    pub upload: u32,
+    /// Sending device info to the server.
+    pub device: u32,
+}
+
+impl Default for Intervals {
+    fn default() -> Self {
+        Intervals { sample: 1, upload: 15, device: 120 }
+    }
+}
+
+impl Intervals {
+    pub fn sample(&self) -> Duration {
+        Duration::from_secs(self.sample.into())
+    }
+
+    pub fn upload(&self) -> Duration {
+        Duration::from_secs(self.upload.into())
+    }
+
+    pub fn device(&self) -> Duration {
+        Duration::from_secs(self.device.into())
+    }
+}
+
+/// Client settings, read from `client.toml` and overridden by command line flags.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub name: Option<String>,
+    pub server: String,
+    pub device_id: Option<DeviceID>,
+    /// Detected if not given.
+    pub device_type: Option<DeviceType>,
+    pub window_backend: String,
+    pub lock_backend: String,
+    /// For the `command` lock backend.
+    pub lock_command: Option<String>,
+    pub idle_backend: String,
+    /// Seconds without input after which time counts as idle.
+    pub idle_threshold: u64,
+    /// MiB of batches kept while the server is unreachable.
+    pub queue_limit: u64,
+    pub cbor: bool,
+    pub aliases: Option<PathBuf>,
+    pub intervals: Intervals,
+    /// Classification rules, which take priority over the built-in ones.
+    pub rules: Option<Section>,
+    /// Privacy settings, e.g. `deny` to exclude programs and titles.
+    pub privacy: Option<Section>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            name: None,
+            server: "http://127.0.0.1:7246".to_owned(),
+            device_id: None,
+            device_type: None,
+            window_backend: "auto".to_owned(),
+            lock_backend: "auto".to_owned(),
+            lock_command: None,
+            idle_backend: "auto".to_owned(),
+            idle_threshold: 300,
+            queue_limit: 64,
+            cbor: false,
+            aliases: None,
+            intervals: Intervals::default(),
+            rules: None,
+            privacy: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_toml(s: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(s)?)
+    }
+
+    /// Reads the config file at `path`, with the defaults if there is none.
+    /// Files it refers to are relative to its directory.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(format!("can't read {}: {}", path.display(), e).into()),
+        };
+        let mut config = Config::from_toml(&data).map_err(|e| format!("invalid config {}: {}", path.display(), e))?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        if let Some(aliases) = &mut config.aliases {
+            *aliases = dir.join(&aliases);
+        }
+        for section in config.rules.iter_mut().chain(config.privacy.iter_mut()) {
+            if let Section::File(file) = section {
+                *file = dir.join(&file);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Replaces settings with those given as flags.
+    pub fn apply_args(&mut self, matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+        let string = |name| matches.value_of(name).map(str::to_owned);
+        if let Some(name) = string("name") {
+            self.name = Some(name);
+        }
+        if let Some(server) = string("server") {
+            self.server = server;
+        }
+        if let Some(id) = matches.value_of("device-id") {
+            self.device_id = Some(id.parse()?);
+        }
+        if let Some(type_) = matches.value_of("device-type") {
+            self.device_type = Some(type_.parse()?);
+        }
+        if let Some(backend) = string("window-backend") {
+            self.window_backend = backend;
+        }
+        if let Some(backend) = string("lock-backend") {
+            self.lock_backend = backend;
+        }
+        if let Some(command) = string("lock-command") {
+            self.lock_command = Some(command);
+        }
+        if let Some(backend) = string("idle-backend") {
+            self.idle_backend = backend;
+        }
+        if let Some(threshold) = matches.value_of("idle-threshold") {
+            self.idle_threshold = threshold.parse()?;
+        }
+        if let Some(limit) = matches.value_of("queue-limit") {
+            self.queue_limit = limit.parse()?;
+        }
+        if matches.is_present("cbor") {
+            self.cbor = true;
+        }
+        if let Some(path) = matches.value_of("aliases") {
+            self.aliases = Some(path.into());
+        }
+        if let Some(path) = matches.value_of("rules") {
+            self.rules = Some(Section::File(path.into()));
+        }
+        if let Some(path) = matches.value_of("privacy") {
+            self.privacy = Some(Section::File(path.into()));
+        }
+        Ok(())
+    }
+
+    /// Checks what the file format can't.
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.name.is_none() {
+            return Err(format!("no name given, use --name or set name in {}", default_path().display()).into());
+        }
+        // the command line checks its own values, but the file's are only checked here
+        let backends = [
+            ("window_backend", &self.window_backend, WINDOW_BACKENDS),
+            ("lock_backend", &self.lock_backend, crate::lock::BACKENDS),
+            ("idle_backend", &self.idle_backend, IDLE_BACKENDS),
+        ];
+        for (setting, backend, known) in backends.iter() {
+            if !known.contains(&backend.as_str()) {
+                return Err(format!("unknown {} \"{}\", expected one of {}", setting, backend, known.join(", ")).into());
+            }
+        }
+        if self.intervals.sample == 0 || self.intervals.upload == 0 || self.intervals.device == 0 {
+            return Err("intervals must be at least 1 second".into());
+        }
+        Ok(())
+    }
+
+    /// The configured rules followed by the built-in ones.
+    pub fn rules(&self) -> Result<RuleSet, Box<dyn Error>> {
+        let mut rules = match &self.rules {
+            Some(Section::File(path)) => RuleSet::load(path)?,
+            Some(Section::Inline(value)) => value.clone().try_into()?,
+            None => return Ok(RuleSet::defaults().clone()),
+        };
+        rules.extend(RuleSet::defaults().clone());
+        Ok(rules)
+    }
+
+    pub fn privacy(&self) -> Result<Privacy, Box<dyn Error>> {
+        match &self.privacy {
+            Some(Section::File(path)) => Privacy::load(path),
+            Some(Section::Inline(value)) => Ok(value.clone().try_into()?),
+            None => Ok(Privacy::default()),
+        }
+    }
+
+    /// The settings as they would be written in `client.toml`.
+    pub fn to_toml(&self) -> Result<String, Box<dyn Error>> {
+        // through a Value, which puts tables after plain values as TOML requires
+        Ok(toml::to_string(&toml::Value::try_from(self)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+name = "allen"
+server = "http://monitor.lan:7246"
+lock_backend = "logind"
+aliases = "aliases.toml"
+rules = "rules.toml"
+
+[intervals]
+upload = 60
+
+[privacy]
+deny = [{ program = "keepassxc" }]
+"#;
+
+    #[test]
+    fn file_and_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.toml");
+        fs::write(&path, CONFIG).unwrap();
+
+        let mut config = Config::load(&path).unwrap();
+        assert_eq!(config.name.as_deref(), Some("allen"));
+        assert_eq!((config.intervals.sample, config.intervals.upload, config.intervals.device), (1, 60, 120));
+        assert_eq!(config.aliases, Some(dir.path().join("aliases.toml")));
+        assert!(matches!(&config.rules, Some(Section::File(file)) if *file == dir.path().join("rules.toml")));
+        assert!(config.privacy().unwrap().is_private("keepassxc", "Passwords"));
+
+        let matches = crate::app().get_matches_from(vec!["monitor-linux", "--server", "http://localhost:7246", "--rules", "mine.json"]);
+        config.apply_args(&matches).unwrap();
+        assert_eq!(config.server, "http://localhost:7246");
+        assert_eq!(config.lock_backend, "logind");
+        assert!(matches!(&config.rules, Some(Section::File(file)) if *file == Path::new("mine.json")));
+        config.check().unwrap();
+
+        let printed = Config::from_toml(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(printed.server, config.server);
+        assert_eq!(printed.intervals.upload, 60);
+        assert!(matches!(printed.privacy, Some(Section::Inline(_))));
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::load(Path::new("/nonexistent/client.toml")).unwrap();
+        assert_eq!(config.server, "http://127.0.0.1:7246");
+        assert!(config.check().is_err());
+        assert!(Config::from_toml("naem = \"typo\"").is_err());
+
+        let wayland = Config::from_toml("name = \"allen\"\nwindow_backend = \"wayland\"").unwrap();
+        assert!(wayland.check().unwrap_err().to_string().contains("window_backend"));
+        let idle = Config::from_toml("name = \"allen\"\nidle_backend = \"xss\"").unwrap();
+        assert!(idle.check().is_err());
+    }
+}
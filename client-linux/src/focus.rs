@@ -0,0 +1,128 @@
+use std::error::Error;
+use monitor::http::{self, Interval};
+use monitor::source::{WindowSource, Windows};
+use monitor::ActiveProgram;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::log;
+use crate::process::{WindowInfo, X11, X11Source};
+
+/// A change to the focus, the window list or the title of the focused window.
+#[derive(Clone, Debug)]
+pub struct Change {
+    /// Unix time in milliseconds.
+    pub at: u64,
+    pub windows: Windows<WindowInfo>,
+}
+
+/// Watches for X11 changes in a thread of its own. The channel closes if that fails.
+pub fn watch_x11() -> Result<UnboundedReceiver<Change>, Box<dyn Error>> {
+    // events go to the connection that asked for them, so the watcher needs its own
+    let x11 = X11::connect(None)?;
+    x11.watch(x11.root(), true)?;
+    let (sender, receiver) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let source = X11Source { x11, idle: None, locker: None };
+        if let Err(e) = watch_x11_changes(&source, &sender) {
+            log(1, format!("stopped watching windows: {}", e));
+        }
+    });
+    Ok(receiver)
+}
+
+fn watch_x11_changes(source: &X11Source, sender: &UnboundedSender<Change>) -> Result<(), Box<dyn Error>> {
+    let x11 = &source.x11;
+    // the focused window is watched too, for title changes
+    let mut active = x11.get_active_window()?;
+    if active != 0 {
+        x11.watch(active, true)?;
+    }
+    loop {
+        x11.wait_for_change()?;
+        let at = http::unix_millis();
+        let now_active = x11.get_active_window()?;
+        if now_active != active {
+            if active != 0 {
+                x11.watch(active, false)?;
+            }
+            if now_active != 0 {
+                x11.watch(now_active, true)?;
+            }
+            active = now_active;
+        }
+        if sender.send(Change { at, windows: source.windows()? }).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// The next change, or never if there is no watcher.
+pub async fn next_change(changes: &mut Option<UnboundedReceiver<Change>>) -> Option<Change> {
+    match changes {
+        Some(changes) => changes.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Turns focus changes into intervals with exact start and end times.
+#[derive(Debug, Default)]
+pub struct FocusTimer {
+    current: Option<(ActiveProgram, u64)>,
+}
+
+impl FocusTimer {
+    /// Ends the current interval at `at` and starts one for `program`, unless it is already current.
+    /// `None` means nothing is in use, e.g. because the user is idle.
+    pub fn switch(&mut self, intervals: &mut Vec<Interval>, program: Option<ActiveProgram>, at: u64) {
+        if self.current.as_ref().map(|(current, _)| current) == program.as_ref() {
+            return;
+        }
+        self.flush(intervals, at);
+        self.current = program.map(|program| (program, at));
+    }
+
+    /// Adds the current interval up to `at` to `intervals`, e.g. before they are sent,
+    /// and returns the part that was added.
+    pub fn flush(&mut self, intervals: &mut Vec<Interval>, at: u64) -> Option<Interval> {
+        let (program, start) = self.current.as_mut()?;
+        if at <= *start {
+            return None;
+        }
+        let interval = Interval { program: program.clone(), start: *start, end: at };
+        http::push_interval(intervals, interval.clone());
+        *start = at;
+        Some(interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(name: &str) -> ActiveProgram {
+        ActiveProgram { program: name.to_owned(), subprogram: None }
+    }
+
+    #[test]
+    fn focus_timer() {
+        let mut intervals = Vec::new();
+        let mut timer = FocusTimer::default();
+        timer.switch(&mut intervals, Some(program("firefox")), 1_000);
+        timer.switch(&mut intervals, Some(program("firefox")), 1_500);
+        timer.switch(&mut intervals, Some(program("code")), 2_120);
+        timer.switch(&mut intervals, Some(program("firefox")), 2_250);
+        timer.switch(&mut intervals, None, 4_000);
+        timer.switch(&mut intervals, None, 9_000);
+        timer.switch(&mut intervals, Some(program("code")), 10_000);
+        timer.flush(&mut intervals, 10_400);
+        timer.flush(&mut intervals, 10_900);
+
+        let found: Vec<_> = intervals.iter().map(|i| (i.program.program.as_str(), i.start, i.end)).collect();
+        assert_eq!(found, vec![
+            ("firefox", 1_000, 2_120),
+            ("code", 2_120, 2_250),
+            ("firefox", 2_250, 4_000),
+            ("code", 10_000, 10_900),
+        ]);
+    }
+}
@@ -0,0 +1,115 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use monitor::http::DeviceID;
+
+/// Directory for files the client keeps between runs, `$XDG_STATE_HOME/monitor`.
+pub fn state_dir() -> PathBuf {
+    let base = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&std::env::var_os("HOME").unwrap_or_default()).join(".local/state"),
+    };
+    base.join("monitor")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Stable identity of this machine, sent to the server to get a `DeviceID`.
+///
+/// Derived from `machine_id` (normally `/etc/machine-id`), which is hashed so the
+/// raw id never leaves the machine. Without one, a random id is generated
+/// and kept in `state_dir`.
+pub fn machine_identity(machine_id: &Path, state_dir: &Path) -> io::Result<String> {
+    if let Ok(id) = fs::read_to_string(machine_id) {
+        let id = id.trim();
+        if !id.is_empty() {
+            let hash = Sha256::new().chain(b"monitor:").chain(id.as_bytes()).finalize();
+            return Ok(to_hex(&hash[..16]));
+        }
+    }
+
+    let path = state_dir.join("device-id");
+    if let Ok(id) = fs::read_to_string(&path) {
+        let id = id.trim();
+        if !id.is_empty() {
+            return Ok(id.to_owned());
+        }
+    }
+
+    let mut bytes = [0u8; 16];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let id = to_hex(&bytes);
+    fs::create_dir_all(state_dir)?;
+    fs::write(&path, &id)?;
+    Ok(id)
+}
+
+/// A device id the server gave out, kept so later runs don't need the server to start.
+#[derive(Debug, Serialize, Deserialize)]
+struct Registered {
+    server: String,
+    name: String,
+    id: DeviceID,
+}
+
+/// The id registered for `name` at `server` by an earlier run.
+pub fn registered_id(state_dir: &Path, server: &str, name: &str) -> Option<DeviceID> {
+    let data = fs::read(state_dir.join("registered.json")).ok()?;
+    let registered: Registered = serde_json::from_slice(&data).ok()?;
+    if registered.server == server && registered.name == name {
+        Some(registered.id)
+    } else {
+        None
+    }
+}
+
+pub fn save_registered_id(state_dir: &Path, server: &str, name: &str, id: DeviceID) -> io::Result<()> {
+    let registered = Registered { server: server.to_owned(), name: name.to_owned(), id };
+    fs::create_dir_all(state_dir)?;
+    fs::write(state_dir.join("registered.json"), serde_json::to_vec(&registered)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_machine_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let machine_id = dir.path().join("machine-id");
+        fs::write(&machine_id, "0123456789abcdef0123456789abcdef\n").unwrap();
+
+        let id = machine_identity(&machine_id, &dir.path().join("state")).unwrap();
+        assert_eq!(id.len(), 32);
+        assert!(!id.starts_with("0123"));
+        assert_eq!(machine_identity(&machine_id, &dir.path().join("state")).unwrap(), id);
+        assert!(!dir.path().join("state").exists());
+    }
+
+    #[test]
+    fn generated() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("machine-id");
+        let state = dir.path().join("state");
+
+        let id = machine_identity(&missing, &state).unwrap();
+        assert_eq!(id.len(), 32);
+        assert_eq!(fs::read_to_string(state.join("device-id")).unwrap(), id);
+        assert_eq!(machine_identity(&missing, &state).unwrap(), id);
+    }
+
+    #[test]
+    fn registered() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(registered_id(dir.path(), "http://a:7246", "allen"), None);
+
+        save_registered_id(dir.path(), "http://a:7246", "allen", 300).unwrap();
+        assert_eq!(registered_id(dir.path(), "http://a:7246", "allen"), Some(300));
+        assert_eq!(registered_id(dir.path(), "http://b:7246", "allen"), None);
+        assert_eq!(registered_id(dir.path(), "http://a:7246", "bob"), None);
+    }
+}
@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::time::Duration;
+use x11rb::connection::Connection;
+use x11rb::protocol::screensaver::ConnectionExt;
+use x11rb::rust_connection::RustConnection;
+
+use crate::log;
+
+/// Reads how long the user hasn't touched keyboard or mouse, using the MIT-SCREEN-SAVER extension.
+pub struct IdleMonitor {
+    conn: RustConnection,
+    root: u32,
+}
+
+impl IdleMonitor {
+    pub fn connect() -> Result<Self, Box<dyn Error>> {
+        let (conn, screen) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen].root;
+        conn.screensaver_query_version(1, 1)?.reply()?;
+        Ok(IdleMonitor { conn, root })
+    }
+
+    pub fn idle_time(&self) -> Result<Duration, Box<dyn Error>> {
+        let info = self.conn.screensaver_query_info(self.root)?.reply()?;
+        Ok(Duration::from_millis(info.ms_since_user_input.into()))
+    }
+}
+
+/// Whether the idle `backend` calls for the X11 idle time. Under Wayland the X server only
+/// sees input to X11 windows, so `auto` leaves it out there, but i3 and other X11 window
+/// managers get it whichever window backend is used.
+pub fn wants_x11(backend: &str, wayland: bool) -> bool {
+    match backend {
+        "none" => false,
+        "x11" => true,
+        _ => !wayland,
+    }
+}
+
+/// The idle monitor for `backend`, if any. It's only an error if `x11` was asked for and can't be had.
+pub fn monitor(backend: &str) -> Result<Option<IdleMonitor>, Box<dyn Error>> {
+    let wayland = std::env::var_os("WAYLAND_DISPLAY").is_some();
+    if !wants_x11(backend, wayland) {
+        if backend != "none" {
+            log(1, "no idle time under Wayland, not tracking idle");
+        }
+        return Ok(None);
+    }
+    match IdleMonitor::connect() {
+        Ok(monitor) => Ok(Some(monitor)),
+        Err(e) if backend == "x11" => Err(format!("can't read idle time: {}", e).into()),
+        Err(e) => {
+            log(1, format!("can't read idle time, not tracking idle: {}", e));
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backends() {
+        // i3 runs on X11, so it keeps idle tracking with the sway window backend
+        assert!(wants_x11("auto", false));
+        assert!(!wants_x11("auto", true));
+        assert!(wants_x11("x11", true));
+        assert!(!wants_x11("none", false));
+    }
+}
@@ -0,0 +1,307 @@
+use std::error::Error;
+use std::convert::TryFrom;
+use std::process::{Command, Output};
+use zbus::blocking::Connection;
+use zbus::zvariant::OwnedValue;
+
+/// A way of finding out whether the session is locked.
+pub trait LockDetector {
+    fn name(&self) -> &str;
+
+    /// Fails if the backend doesn't work on this system, e.g. because the service isn't running.
+    fn is_locked(&self) -> Result<bool, Box<dyn Error>>;
+}
+
+/// `LockedHint` of the systemd-logind session.
+pub struct Logind {
+    conn: Connection,
+    session: String,
+}
+
+impl Logind {
+    /// Watches the session of this process on the system bus.
+    pub fn system() -> Result<Self, Box<dyn Error>> {
+        Ok(Logind::new(Connection::system()?, "/org/freedesktop/login1/session/auto"))
+    }
+
+    pub fn new(conn: Connection, session: &str) -> Self {
+        Logind { conn, session: session.to_owned() }
+    }
+}
+
+impl LockDetector for Logind {
+    fn name(&self) -> &str {
+        "logind"
+    }
+
+    fn is_locked(&self) -> Result<bool, Box<dyn Error>> {
+        let reply = self.conn.call_method(
+            Some("org.freedesktop.login1"),
+            self.session.as_str(),
+            Some("org.freedesktop.DBus.Properties"),
+            "Get",
+            &("org.freedesktop.login1.Session", "LockedHint"),
+        )?;
+        let value: OwnedValue = reply.body().deserialize()?;
+        Ok(bool::try_from(value)?)
+    }
+}
+
+/// `GetActive` of a screensaver service on the session bus.
+pub struct ScreenSaver {
+    conn: Connection,
+    name: &'static str,
+    service: &'static str,
+    path: &'static str,
+}
+
+impl ScreenSaver {
+    /// The freedesktop.org screensaver interface, implemented by KDE, XFCE and others.
+    pub fn freedesktop(conn: Connection) -> Self {
+        ScreenSaver { conn, name: "freedesktop", service: "org.freedesktop.ScreenSaver", path: "/org/freedesktop/ScreenSaver" }
+    }
+
+    pub fn gnome(conn: Connection) -> Self {
+        ScreenSaver { conn, name: "gnome", service: "org.gnome.ScreenSaver", path: "/org/gnome/ScreenSaver" }
+    }
+}
+
+impl LockDetector for ScreenSaver {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn is_locked(&self) -> Result<bool, Box<dyn Error>> {
+        let reply = self.conn.call_method(Some(self.service), self.path, Some(self.service), "GetActive", &())?;
+        Ok(reply.body().deserialize()?)
+    }
+}
+
+/// Runs a command and decides from its output whether the session is locked.
+pub struct CommandQuery {
+    name: String,
+    program: String,
+    args: Vec<String>,
+    locked: fn(&Output) -> Result<bool, Box<dyn Error>>,
+}
+
+fn stdout_ends_with_active(output: &Output) -> Result<bool, Box<dyn Error>> {
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_owned().into());
+    }
+    let first = std::str::from_utf8(&output.stdout)?.lines().next().unwrap_or("").trim();
+    Ok(first.ends_with("active") && !first.ends_with("inactive"))
+}
+
+impl CommandQuery {
+    pub fn xfce() -> Self {
+        CommandQuery {
+            name: "xfce".to_owned(),
+            program: "xfce4-screensaver-command".to_owned(),
+            args: vec!["-q".to_owned()],
+            locked: stdout_ends_with_active,
+        }
+    }
+
+    pub fn light_locker() -> Self {
+        CommandQuery {
+            name: "light-locker".to_owned(),
+            program: "light-locker-command".to_owned(),
+            args: vec!["-q".to_owned()],
+            locked: stdout_ends_with_active,
+        }
+    }
+
+    /// Prints e.g. "XScreenSaver 6.00: screen locked since ...".
+    pub fn xscreensaver() -> Self {
+        CommandQuery {
+            name: "xscreensaver".to_owned(),
+            program: "xscreensaver-command".to_owned(),
+            args: vec!["-time".to_owned()],
+            locked: |output| {
+                if !output.status.success() {
+                    return Err(String::from_utf8_lossy(&output.stderr).trim().to_owned().into());
+                }
+                Ok(std::str::from_utf8(&output.stdout)?.contains("screen locked"))
+            },
+        }
+    }
+
+    /// A shell command that exits with 0 when the session is locked.
+    pub fn generic(command: &str) -> Self {
+        CommandQuery {
+            name: "command".to_owned(),
+            program: "sh".to_owned(),
+            args: vec!["-c".to_owned(), command.to_owned()],
+            locked: |output| Ok(output.status.success()),
+        }
+    }
+}
+
+impl LockDetector for CommandQuery {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_locked(&self) -> Result<bool, Box<dyn Error>> {
+        let output = Command::new(&self.program).args(&self.args).output()?;
+        (self.locked)(&output)
+    }
+}
+
+pub const BACKENDS: &[&str] = &["auto", "gnome", "freedesktop", "xfce", "light-locker", "xscreensaver", "logind", "command", "none"];
+
+/// Creates the backend called `name`, one of `BACKENDS`. `command` is needed for "command".
+/// Returns `None` for "none", or for "auto" if no backend works.
+pub fn backend(name: &str, command: Option<&str>) -> Result<Option<Box<dyn LockDetector>>, Box<dyn Error>> {
+    let backend: Box<dyn LockDetector> = match name {
+        "auto" => return Ok(detect()),
+        "none" => return Ok(None),
+        "gnome" => Box::new(ScreenSaver::gnome(Connection::session()?)),
+        "freedesktop" => Box::new(ScreenSaver::freedesktop(Connection::session()?)),
+        "xfce" => Box::new(CommandQuery::xfce()),
+        "light-locker" => Box::new(CommandQuery::light_locker()),
+        "xscreensaver" => Box::new(CommandQuery::xscreensaver()),
+        "logind" => Box::new(Logind::system()?),
+        "command" => Box::new(CommandQuery::generic(command.ok_or("the command lock backend needs a command")?)),
+        _ => return Err(format!("unknown lock backend: {}", name).into()),
+    };
+    Ok(Some(backend))
+}
+
+/// Picks the first backend that works, from most to least specific.
+/// logind comes last because many desktops never set `LockedHint`.
+pub fn detect() -> Option<Box<dyn LockDetector>> {
+    let mut candidates: Vec<Box<dyn LockDetector>> = Vec::new();
+    if let Ok(conn) = Connection::session() {
+        candidates.push(Box::new(ScreenSaver::gnome(conn.clone())));
+        candidates.push(Box::new(ScreenSaver::freedesktop(conn)));
+    }
+    candidates.push(Box::new(CommandQuery::xfce()));
+    candidates.push(Box::new(CommandQuery::light_locker()));
+    candidates.push(Box::new(CommandQuery::xscreensaver()));
+    if let Ok(logind) = Logind::system() {
+        candidates.push(Box::new(logind));
+    }
+
+    candidates.into_iter().find(|backend| backend.is_locked().is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Stdio};
+    use std::sync::{Arc, Mutex};
+
+    /// A dbus-daemon with its own session bus, killed on drop.
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        /// `None` if dbus-daemon isn't installed, unless `$MONITOR_REQUIRE_DBUS` is set, as it should be in CI.
+        fn start() -> Option<Self> {
+            let mut daemon = match Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn() {
+                Ok(daemon) => daemon,
+                Err(e) if std::env::var_os("MONITOR_REQUIRE_DBUS").is_some() => panic!("can't start dbus-daemon: {}", e),
+                Err(e) => {
+                    eprintln!("skipping, can't start dbus-daemon: {}", e);
+                    return None;
+                }
+            };
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            Some(PrivateBus { daemon, address: address.trim().to_owned() })
+        }
+
+        fn connect(&self) -> Connection {
+            zbus::blocking::connection::Builder::address(self.address.as_str()).unwrap().build().unwrap()
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    struct FakeScreenSaver(Arc<Mutex<bool>>);
+
+    #[zbus::interface(name = "org.freedesktop.ScreenSaver")]
+    impl FakeScreenSaver {
+        fn get_active(&self) -> bool {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    struct FakeSession(Arc<Mutex<bool>>);
+
+    #[zbus::interface(name = "org.freedesktop.login1.Session")]
+    impl FakeSession {
+        #[zbus(property)]
+        fn locked_hint(&self) -> bool {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[test]
+    fn screensaver() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let active = Arc::new(Mutex::new(false));
+        let _server = zbus::blocking::connection::Builder::address(bus.address.as_str()).unwrap()
+            .name("org.freedesktop.ScreenSaver").unwrap()
+            .serve_at("/org/freedesktop/ScreenSaver", FakeScreenSaver(active.clone())).unwrap()
+            .build().unwrap();
+
+        let backend = ScreenSaver::freedesktop(bus.connect());
+        assert!(!backend.is_locked().unwrap());
+        *active.lock().unwrap() = true;
+        assert!(backend.is_locked().unwrap());
+
+        assert!(ScreenSaver::gnome(bus.connect()).is_locked().is_err());
+    }
+
+    #[test]
+    fn logind() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let locked = Arc::new(Mutex::new(true));
+        let _server = zbus::blocking::connection::Builder::address(bus.address.as_str()).unwrap()
+            .name("org.freedesktop.login1").unwrap()
+            .serve_at("/org/freedesktop/login1/session/_31", FakeSession(locked.clone())).unwrap()
+            .build().unwrap();
+
+        let backend = Logind::new(bus.connect(), "/org/freedesktop/login1/session/_31");
+        assert!(backend.is_locked().unwrap());
+        *locked.lock().unwrap() = false;
+        assert!(!backend.is_locked().unwrap());
+    }
+
+    #[test]
+    fn commands() {
+        assert!(CommandQuery::generic("true").is_locked().unwrap());
+        assert!(!CommandQuery::generic("false").is_locked().unwrap());
+        assert!(CommandQuery::xscreensaver_with("echo 'XScreenSaver 6.00: screen locked since Mon'").is_locked().unwrap());
+        assert!(!CommandQuery::xscreensaver_with("echo 'XScreenSaver 6.00: screen non-blanked since Mon'").is_locked().unwrap());
+        assert!(CommandQuery::xscreensaver_with("exit 1").is_locked().is_err());
+    }
+
+    impl CommandQuery {
+        /// `xscreensaver()` with its command replaced.
+        fn xscreensaver_with(command: &str) -> Self {
+            CommandQuery { program: "sh".to_owned(), args: vec!["-c".to_owned(), command.to_owned()], ..CommandQuery::xscreensaver() }
+        }
+    }
+}
@@ -1,15 +1,29 @@
+mod focus;
+mod backoff;
+mod config;
+mod identity;
+mod idle;
+mod lock;
 mod process;
+mod queue;
+mod sample;
+mod sway;
+mod sysinfo;
+mod terminal;
 use std::{borrow::{Borrow, Cow}, collections::HashMap, env::args, error::Error, hash::Hash};
 
-use monitor::http::{Device, DeviceData, DeviceID};
+use monitor::http::{self, Device, DeviceData, DeviceID};
+use monitor::names::AppNames;
+use monitor::source::WindowSource;
 use tokio::time;
 extern crate clap;
 use clap::App;
 
 #[test]
 fn test_active() {
-    let active_id = process::get_active_window().unwrap();
-    let data = process::get_window_info(active_id).unwrap().unwrap();
+    let x11 = process::X11::connect(None).unwrap();
+    let active_id = x11.get_active_window().unwrap();
+    let data = x11.get_window_info(active_id).unwrap().unwrap();
     println!("id = {}", active_id);
     println!("program = {}", &data.program);
     println!("title = {}", &data.title);
@@ -48,27 +62,43 @@ fn test_device_id() {
     println!("{:?}", get_device_id());
 }
 
-fn get_device_info() -> Result<monitor::http::DeviceData, Box<dyn Error>> {
-    let release_data = os_release::OsRelease::new()?;
-
-    Ok(monitor::http::DeviceData {
-        type_ : monitor::http::DeviceType::Laptop,
+fn get_device_info(device_type: monitor::http::DeviceType, window_manager: Option<String>) -> monitor::http::DeviceData {
+    monitor::http::DeviceData {
+        type_ : device_type,
         os: "Linux".to_owned(),
-        distro: Some(release_data.pretty_name),
-    })
+        // not every system has /etc/os-release
+        distro: os_release::OsRelease::new().ok().map(|release| release.pretty_name),
+        hostname: sysinfo::hostname(),
+        kernel: sysinfo::kernel(),
+        client_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+        cpu: sysinfo::cpu(),
+        memory_mib: sysinfo::memory_mib(),
+        monitors: sysinfo::monitors(),
+        desktop: sysinfo::desktop(),
+        window_manager,
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let matches = clap::App::new("monitor-linux")
+/// Command line flags. Those without a value given leave the config file's setting.
+fn app() -> App<'static, 'static> {
+    App::new("monitor-linux")
         .about("monitor client for X11 / Linux")
+        .arg(clap::Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Config file, ~/.config/monitor/client.toml by default. Flags override its settings"))
+        .arg(clap::Arg::with_name("print-config")
+            .long("print-config")
+            .help("Print the configuration with flags applied and exit"))
         .arg(clap::Arg::with_name("name")
             .short("n")
             .long("name")
             .value_name("NAME")
             .help("Your name, given to the monitor server")
             .takes_value(true)
-            .required(true))
+            .required(false))
         .arg(clap::Arg::with_name("device-id")
             .short("d")
             .long("device-id")
@@ -76,82 +106,225 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .help("Device ID of this computer")
             .takes_value(true)
             .required(false))
+        .arg(clap::Arg::with_name("device-type")
+            .long("device-type")
+            .value_name("TYPE")
+            .help("Type of this computer, detected if not given")
+            .takes_value(true)
+            .possible_values(&["desktop", "laptop", "phone", "tablet", "other"])
+            .required(false))
         .arg(clap::Arg::with_name("server")
             .short("s")
             .long("server")
             .takes_value(true)
             .value_name("http://HOST:PORT")
             .help("URL of the monitor server")
-            .required(false)
-            .default_value("http://127.0.0.1:7246"))
-        .get_matches();
-
-    let name = matches.value_of("name").unwrap();
-    let device_id = matches.value_of("device-id").map(<DeviceID as std::str::FromStr>::from_str).and_then(Result::ok).unwrap_or_else(|| get_device_id().unwrap());
-    let server = matches.value_of("server").unwrap();
-
-    let mut interval = time::interval(time::Duration::from_secs(1));
-    let client = reqwest::Client::new();
-    let mut seconds = 0;
-    let mut http_data: monitor::http::Add = monitor::http::Add::new(device_id);
-
-    log(0, format!("username: {}", name));
-    log(0, format!("device id: {}", device_id));
+            .required(false))
+        .arg(clap::Arg::with_name("rules")
+            .short("r")
+            .long("rules")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("TOML or JSON file with extra classification rules")
+            .required(false))
+        .arg(clap::Arg::with_name("privacy")
+            .long("privacy")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("TOML or JSON file with privacy settings")
+            .required(false))
+        .arg(clap::Arg::with_name("aliases")
+            .long("aliases")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("TOML file with program display names for programs without a .desktop file")
+            .required(false))
+        .arg(clap::Arg::with_name("idle-threshold")
+            .long("idle-threshold")
+            .takes_value(true)
+            .value_name("SECS")
+            .help("Count time as idle after this many seconds without input"))
+        .arg(clap::Arg::with_name("lock-backend")
+            .long("lock-backend")
+            .takes_value(true)
+            .value_name("BACKEND")
+            .possible_values(lock::BACKENDS)
+            .help("How to detect whether the session is locked"))
+        .arg(clap::Arg::with_name("window-backend")
+            .long("window-backend")
+            .takes_value(true)
+            .value_name("BACKEND")
+            .possible_values(config::WINDOW_BACKENDS)
+            .help("Where to read windows from; sway also works for i3"))
+        .arg(clap::Arg::with_name("idle-backend")
+            .long("idle-backend")
+            .takes_value(true)
+            .value_name("BACKEND")
+            .possible_values(config::IDLE_BACKENDS)
+            .help("How to read the time since the last input; x11 needs the screen saver extension"))
+        .arg(clap::Arg::with_name("lock-command")
+            .long("lock-command")
+            .takes_value(true)
+            .value_name("CMD")
+            .help("Shell command that exits with 0 when the session is locked, for --lock-backend command")
+            .required(false))
+        .arg(clap::Arg::with_name("queue-limit")
+            .long("queue-limit")
+            .takes_value(true)
+            .value_name("MIB")
+            .help("Largest size of the queue of batches waiting for the server"))
+        .arg(clap::Arg::with_name("cbor")
+            .long("cbor")
+            .help("Send data as CBOR instead of JSON, if the server supports it"))
+}
 
-    loop {
-        // Skip counting if session is locked (i.e. user isn't using the computer)
-        if process::is_locked() {
-            interval.tick().await;
-            continue;
-        }
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let matches = app().get_matches();
+    let mut config = match matches.value_of("config") {
+        Some(path) => {
+            if !std::path::Path::new(path).exists() {
+                return Err(format!("config file {} doesn't exist", path).into());
+            }
+            config::Config::load(path.as_ref())?
+        },
+        None => config::Config::load(&config::default_path())?,
+    };
+    config.apply_args(&matches)?;
+    if matches.is_present("print-config") {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    config.check()?;
 
-        match add_data(&mut http_data) {
-            Err(e) => {
-                log(2, e.to_string());
-            },
-            _ => {},
+    let name = config.name.as_deref().unwrap();
+    let server = config.server.as_str();
+    let device_type = match config.device_type {
+        Some(type_) => type_,
+        None => sysinfo::device_type("/sys".as_ref()),
+    };
+    let rules = config.rules()?;
+    let privacy = config.privacy()?;
+    let idle_threshold = time::Duration::from_secs(config.idle_threshold);
+    let locker = lock::backend(&config.lock_backend, config.lock_command.as_deref())?;
+    match &locker {
+        Some(locker) => log(0, format!("lock detection: {}", locker.name())),
+        None => log(1, "no lock detection, counting time while locked"),
+    }
+    let idle = idle::monitor(&config.idle_backend)?;
+    let (source, changes, window_manager): (Box<dyn WindowSource<Window = process::WindowInfo>>, _, _) = match (config.window_backend.as_str(), sway::Sway::from_env()) {
+        ("sway", None) => return Err("SWAYSOCK and I3SOCK are not set".into()),
+        ("sway", Some(sway)) | ("auto", Some(sway)) => {
+            let changes = sway.watch();
+            let window_manager = sway.window_manager().ok();
+            (Box::new(sway::SwaySource { sway, idle, locker }), changes, window_manager)
+        },
+        _ => {
+            let x11 = process::X11::connect(None)?;
+            let window_manager = x11.window_manager().unwrap_or_else(|e| {
+                log(1, format!("can't read the window manager: {}", e));
+                None
+            });
+            (Box::new(process::X11Source { x11, idle, locker }), focus::watch_x11(), window_manager)
+        },
+    };
+    let mut changes = match changes {
+        Ok(changes) => Some(changes),
+        Err(e) => {
+            log(1, format!("can't watch for window changes, polling instead: {}", e));
+            None
         }
+    };
+    let mut names = AppNames::installed();
+    if let Some(path) = &config.aliases {
+        names.load_aliases(path)?;
+    }
 
+    // a server that accepts connections but never answers would otherwise hold up every upload
+    let client = reqwest::Client::builder().timeout(time::Duration::from_secs(10)).build()?;
+    let client_id = format!("{:x}-{:x}", monitor::http::unix_millis(), std::process::id());
+    let mut seq = 1;
 
+    log(0, format!("username: {}", name));
 
-        if seconds % 15 == 1 {
-            client.post(format!("{}/api/{}/add", server, name)).json(&http_data).send().await?;
-            http_data = monitor::http::Add::new(device_id);
-        }
-
-        if seconds % 120 == 0 {
-            client.post(format!("{}/api/{}/device", server, name)).json(&monitor::http::Device {
-                id: device_id,
-                data: get_device_info().unwrap()
-            }).send().await?;        
-        }
-
-        seconds += 1;
-        if seconds > 240 {
-            seconds -= 240;
-        }
+    let device_id = config.device_id.or_else(|| identity::registered_id(&identity::state_dir(), server, name));
+    let register = http::Register {
+        machine: identity::machine_identity("/etc/machine-id".as_ref(), &identity::state_dir())?,
+        legacy_id: get_device_id(),
+    };
+    let queue = queue::UploadQueue::open(&identity::state_dir().join("queue"), config.queue_limit * 1024 * 1024)?;
+    if !queue.is_empty() {
+        log(0, format!("{} batches queued from before", queue.len()));
+    }
+    // uploads run on their own, so a slow or unreachable server never holds up sampling
+    let (uploads, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let uploader = queue::Uploader::new(client, server, name, config.cbor, device_id, register, queue);
+    let mut uploading = tokio::spawn(uploader.run(receiver));
 
-        interval.tick().await;
+    let mut http_data = new_batch(&client_id, seq);
+    // with a watcher, ticks only check for locking and idling, which needn't be as often
+    let tick = if changes.is_some() { config.intervals.sample.max(sample::WATCHED_TICK) } else { config.intervals.sample };
+    let mut sampler = sample::Sampler::new(rules, privacy, names, idle_threshold, tick);
+    match source.windows() {
+        Ok(windows) => sampler.change(&mut http_data, focus::Change { at: monitor::http::unix_millis(), windows }),
+        Err(e) => log(2, e.to_string()),
     }
-}
 
-fn add_data(http_data: &mut monitor::http::Add) -> Result<(), Box<dyn Error>> {
-    let active_id = process::get_active_window()?;
-    let windows = process::get_all_windows()?;
-    let mut datas = Vec::new();
-    for id in windows {
-        datas.push((id, process::get_window_info(id)?));
+    let intervals = &config.intervals;
+    let mut sample = time::interval(time::Duration::from_secs(tick.into()));
+    let mut upload = time::interval_at(time::Instant::now() + intervals.upload(), intervals.upload());
+    let mut report = time::interval(intervals.device());
+    // after a suspend, catching up on missed ticks would only count time that wasn't there
+    for interval in [&mut sample, &mut upload, &mut report] {
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
     }
-    for (id, data) in datas {
-        if let Some(data) = data {
-            if id == active_id {
-                *http_data.active.entry(data.clone().into()).or_insert(0) += 1;
-            }
-            *http_data.open.entry(data.into()).or_insert(0) += 1;
+
+    loop {
+        tokio::select! {
+            change = focus::next_change(&mut changes) => {
+                match change {
+                    Some(change) => sampler.change(&mut http_data, change),
+                    None => {
+                        log(1, "lost the window watcher, polling instead");
+                        changes = None;
+                        sampler.tick = intervals.sample;
+                        sample = time::interval(intervals.sample());
+                        sample.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+                    },
+                }
+            },
+            _ = sample.tick() => {
+                sampler.tick(source.as_ref(), &mut http_data, changes.is_none(), monitor::http::unix_millis());
+            },
+            _ = upload.tick() => {
+                sampler.flush(&mut http_data, monitor::http::unix_millis());
+                seq += 1;
+                let batch = std::mem::replace(&mut http_data, new_batch(&client_id, seq));
+                let _ = uploads.send(queue::Upload::Batch(batch));
+            },
+            result = &mut uploading => {
+                // the uploader only stops when the server can't be used, and the queue is kept for a newer client
+                let e = match result {
+                    Ok(Err(e)) => e.to_string(),
+                    Ok(Ok(())) => "uploads stopped".to_owned(),
+                    Err(e) => format!("uploads stopped: {}", e),
+                };
+                log(2, e);
+                std::process::exit(1);
+            },
+            _ = report.tick() => {
+                let _ = uploads.send(queue::Upload::Device(get_device_info(device_type, window_manager.clone())));
+            },
         }
     }
+}
 
-    Ok(())
+/// The device is filled in by the uploader, which may only learn it from the server.
+fn new_batch(client_id: &str, seq: u64) -> monitor::http::Add {
+    let mut http_data = monitor::http::Add::new(0);
+    http_data.client = Some(client_id.to_owned());
+    http_data.seq = Some(seq);
+    http_data
 }
 
 fn log<'a>(level: u8, text: impl Into<Cow<'a, str>>) {
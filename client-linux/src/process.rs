@@ -1,63 +1,191 @@
 use std::borrow::Cow;
-use std::convert::TryInto;
+use std::convert::TryFrom;
 use std::error::Error;
-use std::num::ParseIntError;
-use std::process::Command;
-use crate::log;
-
-pub fn get_active_window() -> Result<u32, Box<dyn Error>> {
-    let output = Command::new("xprop").args(&["-root", "32x", "|$0", "_NET_ACTIVE_WINDOW"]).output()?;
-    let res = output.stdout.split(|&c| c == '|' as u8).skip(1).next().ok_or("error parsing _NET_ACTIVE_WINDOW")?;
-    Ok(u32::from_str_radix(std::str::from_utf8(&res[2..])?, 16)?)
+use x11rb::connection::Connection;
+use x11rb::errors::ReplyError;
+use x11rb::protocol::xproto::{Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, GetPropertyReply, Window};
+use x11rb::protocol::{ErrorKind, Event};
+use x11rb::rust_connection::RustConnection;
+use monitor::source::WindowSource;
+
+use crate::idle::IdleMonitor;
+use crate::lock::LockDetector;
+
+x11rb::atom_manager! {
+    Atoms: AtomsCookie {
+        _NET_ACTIVE_WINDOW,
+        _NET_CLIENT_LIST,
+        _NET_SUPPORTING_WM_CHECK,
+        _NET_WM_NAME,
+        _NET_WM_PID,
+        _NET_WM_WINDOW_TYPE,
+        _NET_WM_WINDOW_TYPE_NORMAL,
+        UTF8_STRING,
+    }
 }
 
-pub fn get_all_windows() -> Result<Vec<u32>, Box<dyn Error>> {
-    let output = Command::new("xprop").args(
-        &["-root", "|$0+", "_NET_CLIENT_LIST"])
-        .output()?;
-    let res = std::str::from_utf8(output.stdout.split(|&c| c == '|' as u8)
-        .skip(1)
-        .next().ok_or("error parsing _NET_CLIENT_LIST")?)?.split(", ")
-        .map(|x| u32::from_str_radix(&x[2..], 16));
-    Ok(res.collect::<Result<Vec<u32>, ParseIntError>>()?)
+/// Connection to the X server, for reading the EWMH properties of windows.
+pub struct X11 {
+    conn: RustConnection,
+    root: Window,
+    atoms: Atoms,
 }
 
 #[derive(Clone, Debug)]
 pub struct WindowInfo {
     pub program: String,
     pub title: String,
+    /// Foreground command, only filled in for terminals.
+    pub command: Option<String>,
+    /// Process that owns the window, if known.
+    pub pid: Option<u32>,
 }
 
-pub fn get_window_info(wid: u32) -> Result<Option<WindowInfo>, Box<dyn Error>> {
-    let output = Command::new("xprop").args(&[
-        "-id", format!("0x{:x}", wid).as_str(),
-        "-f", "_NET_WM_NAME", "8u", "|$0|",
-        "-f", "WM_CLASS", "8s", "|$1|",
-        "-f", "_NET_WM_WINDOW_TYPE", "32a", "|$0",
-        "_NET_WM_NAME", "WM_CLASS", "_NET_WM_WINDOW_TYPE"])
-        .output()?;
-
-    let splits = output.stdout.split(|&c| c == '|' as u8);
-    let mut splits = splits.skip(1);
-    let title = {
-        let title = splits.next().ok_or("error parsing _NET_WM_NAME")?;
-        std::str::from_utf8(&title[1..title.len()-1])?
-    };
-    let mut splits = splits.skip(1);
-    let program = {
-        let program = splits.next().ok_or("error parsing WM_CLASS")?;
-        std::str::from_utf8(&program[1..program.len()-1])?
-    };
-    let mut splits = splits.skip(1);
-    {
-        let type_ = splits.next().ok_or("error parsing _NET_WM_WINDOW_TYPE")?;
-        let type_str = std::str::from_utf8(type_)?;
-        if type_str != "_NET_WM_WINDOW_TYPE_NORMAL" {
+impl X11 {
+    /// Connects to `display`, or to `$DISPLAY` if `None`.
+    pub fn connect(display: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let (conn, screen) = x11rb::connect(display)?;
+        let root = conn.setup().roots[screen].root;
+        let atoms = Atoms::new(&conn)?.reply()?;
+        Ok(X11 { conn, root, atoms })
+    }
+
+    /// Reads a property, or `None` if the window has been destroyed in the meantime.
+    fn property(&self, wid: Window, property: Atom, type_: impl Into<Atom>) -> Result<Option<GetPropertyReply>, Box<dyn Error>> {
+        match self.conn.get_property(false, wid, property, type_, 0, u32::MAX)?.reply() {
+            Ok(reply) => Ok(Some(reply)),
+            Err(ReplyError::X11Error(e)) if e.error_kind == ErrorKind::Window => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn string_property(&self, wid: Window, property: Atom, type_: impl Into<Atom>) -> Result<Option<String>, Box<dyn Error>> {
+        Ok(self.property(wid, property, type_)?
+            .filter(|reply| reply.format == 8 && reply.type_ != u32::from(AtomEnum::NONE))
+            .map(|reply| String::from_utf8_lossy(&reply.value).into_owned()))
+    }
+
+    pub fn get_active_window(&self) -> Result<u32, Box<dyn Error>> {
+        let reply = self.property(self.root, self.atoms._NET_ACTIVE_WINDOW, AtomEnum::WINDOW)?.ok_or("root window is gone")?;
+        let mut value = reply.value32().ok_or("error parsing _NET_ACTIVE_WINDOW")?;
+        Ok(value.next().ok_or("_NET_ACTIVE_WINDOW not set")?)
+    }
+
+    pub fn get_all_windows(&self) -> Result<Vec<u32>, Box<dyn Error>> {
+        let reply = self.property(self.root, self.atoms._NET_CLIENT_LIST, AtomEnum::WINDOW)?.ok_or("root window is gone")?;
+        let windows = reply.value32().ok_or("error parsing _NET_CLIENT_LIST")?.collect();
+        Ok(windows)
+    }
+
+    /// Program and title of a normal window, `None` for docks, dialogs etc.
+    /// and for windows that no longer exist.
+    pub fn get_window_info(&self, wid: u32) -> Result<Option<WindowInfo>, Box<dyn Error>> {
+        let type_ = match self.property(wid, self.atoms._NET_WM_WINDOW_TYPE, AtomEnum::ATOM)? {
+            Some(reply) => reply.value32().and_then(|mut types| types.next()),
+            None => return Ok(None),
+        };
+        if type_ != Some(self.atoms._NET_WM_WINDOW_TYPE_NORMAL) {
             return Ok(None);
         }
+
+        // WM_CLASS is "instance\0class\0"
+        let program = match self.string_property(wid, AtomEnum::WM_CLASS.into(), AtomEnum::STRING)? {
+            Some(class) => class.split('\0').nth(1).unwrap_or("").to_owned(),
+            None => return Ok(None),
+        };
+        let title = match self.string_property(wid, self.atoms._NET_WM_NAME, self.atoms.UTF8_STRING)? {
+            Some(title) => title,
+            None => self.string_property(wid, AtomEnum::WM_NAME.into(), AtomEnum::STRING)?.unwrap_or_default(),
+        };
+
+        let pid = self.get_window_pid(wid)?;
+        Ok(Some(WindowInfo { program, title, command: None, pid }))
+    }
+
+    pub fn get_window_pid(&self, wid: u32) -> Result<Option<u32>, Box<dyn Error>> {
+        Ok(self.property(wid, self.atoms._NET_WM_PID, AtomEnum::CARDINAL)?
+            .and_then(|reply| reply.value32().and_then(|mut value| value.next())))
     }
 
-    Ok(Some(WindowInfo{program: program.to_owned(), title: title.to_owned()}))
+    /// Name of the window manager, from `_NET_SUPPORTING_WM_CHECK`.
+    pub fn window_manager(&self) -> Result<Option<String>, Box<dyn Error>> {
+        let check = self.property(self.root, self.atoms._NET_SUPPORTING_WM_CHECK, AtomEnum::WINDOW)?
+            .and_then(|reply| reply.value32().and_then(|mut value| value.next()));
+        match check {
+            Some(wid) => self.string_property(wid, self.atoms._NET_WM_NAME, self.atoms.UTF8_STRING),
+            None => Ok(None),
+        }
+    }
+
+    pub fn root(&self) -> Window {
+        self.root
+    }
+
+    /// Asks for `PropertyNotify` events of `wid`, or stops them if `watch` is false.
+    pub fn watch(&self, wid: Window, watch: bool) -> Result<(), Box<dyn Error>> {
+        let mask = if watch { EventMask::PROPERTY_CHANGE } else { EventMask::NO_EVENT };
+        self.conn.change_window_attributes(wid, &ChangeWindowAttributesAux::new().event_mask(mask))?;
+        self.conn.flush()?;
+        Ok(())
+    }
+
+    /// Blocks until the focus, the window list or the title of a watched window changes.
+    pub fn wait_for_change(&self) -> Result<(), Box<dyn Error>> {
+        let atoms = [
+            self.atoms._NET_ACTIVE_WINDOW,
+            self.atoms._NET_CLIENT_LIST,
+            self.atoms._NET_WM_NAME,
+            AtomEnum::WM_NAME.into(),
+        ];
+        loop {
+            // errors are for windows that were destroyed before we could watch them
+            if let Event::PropertyNotify(event) = self.conn.wait_for_event()? {
+                if atoms.contains(&event.atom) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// `WindowSource` for X11 sessions.
+pub struct X11Source {
+    pub x11: X11,
+    pub idle: Option<IdleMonitor>,
+    pub locker: Option<Box<dyn LockDetector>>,
+}
+
+impl WindowSource for X11Source {
+    type Window = WindowInfo;
+
+    fn active_window(&self) -> Result<Option<u64>, Box<dyn Error>> {
+        Ok(Some(self.x11.get_active_window()?).filter(|&wid| wid != 0).map(u64::from))
+    }
+
+    fn window_list(&self) -> Result<Vec<u64>, Box<dyn Error>> {
+        Ok(self.x11.get_all_windows()?.into_iter().map(u64::from).collect())
+    }
+
+    fn window_info(&self, id: u64) -> Result<Option<WindowInfo>, Box<dyn Error>> {
+        match u32::try_from(id) {
+            Ok(wid) => self.x11.get_window_info(wid),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn is_locked(&self) -> Result<bool, Box<dyn Error>> {
+        match &self.locker {
+            Some(locker) => locker.is_locked(),
+            None => Ok(false),
+        }
+    }
+
+    fn idle_time(&self) -> Result<Option<std::time::Duration>, Box<dyn Error>> {
+        match &self.idle {
+            Some(idle) => Ok(Some(idle.idle_time()?)),
+            None => Ok(None),
+        }
+    }
 }
 
 extern crate monitor;
@@ -69,23 +197,125 @@ impl monitor::RawWindowData for WindowInfo {
     fn title(&self) -> Cow<'_, str> {
         (&self.title).into()
     }
+
+    fn command(&self) -> Option<Cow<'_, str>> {
+        self.command.as_deref().map(Cow::from)
+    }
 }
 
-pub fn is_locked() -> bool {
-    match Command::new("xfce4-screensaver-command").args(&["-q"]).output() {
-        Ok(output) => {
-            let output = match std::str::from_utf8(&output.stdout) {
-                Ok(v) => v,
-                Err(xfce4_error) => {
-                    log(2, format!("xfce error: {}", xfce4_error));
-                    return false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use x11rb::protocol::xproto::{CreateWindowAux, PropMode, WindowClass};
+    use x11rb::wrapper::ConnectionExt as _;
+
+    /// An Xvfb server, killed on drop.
+    struct Xvfb {
+        server: Child,
+        display: String,
+    }
+
+    impl Xvfb {
+        /// `None` if Xvfb isn't installed, unless `$MONITOR_REQUIRE_XVFB` is set, as it should be in CI.
+        fn start() -> Option<Self> {
+            // -displayfd picks a free display and prints its number
+            let mut server = match Command::new("Xvfb")
+                .args(["-displayfd", "1", "-nolisten", "tcp"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn() {
+                Ok(server) => server,
+                Err(e) if std::env::var_os("MONITOR_REQUIRE_XVFB").is_some() => panic!("can't start Xvfb: {}", e),
+                Err(e) => {
+                    eprintln!("skipping, can't start Xvfb: {}", e);
+                    return None;
                 }
             };
-            let active = output.split("\n").next().map(|x| !x.ends_with("inactive"));
-            return active.unwrap_or(false);
-        },
-        Err(_) => {} // continue
-    };
-
-    false
-}
\ No newline at end of file
+            let mut number = String::new();
+            BufReader::new(server.stdout.take().unwrap()).read_line(&mut number).unwrap();
+            Some(Xvfb { server, display: format!(":{}", number.trim()) })
+        }
+    }
+
+    impl Drop for Xvfb {
+        fn drop(&mut self) {
+            let _ = self.server.kill();
+            let _ = self.server.wait();
+        }
+    }
+
+    /// Plays the part of the window manager and of the applications.
+    struct Fake {
+        conn: RustConnection,
+        root: Window,
+        atoms: Atoms,
+    }
+
+    impl Fake {
+        fn connect(display: &str) -> Self {
+            let (conn, screen) = x11rb::connect(Some(display)).unwrap();
+            let root = conn.setup().roots[screen].root;
+            let atoms = Atoms::new(&conn).unwrap().reply().unwrap();
+            Fake { conn, root, atoms }
+        }
+
+        fn atom(&self, name: &str) -> Atom {
+            self.conn.intern_atom(false, name.as_bytes()).unwrap().reply().unwrap().atom
+        }
+
+        fn window(&self, class: &str, title: &str, type_: Atom, pid: u32) -> Window {
+            let wid = self.conn.generate_id().unwrap();
+            self.conn.create_window(0, wid, self.root, 0, 0, 100, 100, 0, WindowClass::INPUT_OUTPUT, 0, &CreateWindowAux::new()).unwrap();
+            self.conn.change_property8(PropMode::REPLACE, wid, AtomEnum::WM_CLASS, AtomEnum::STRING, class.as_bytes()).unwrap();
+            self.conn.change_property8(PropMode::REPLACE, wid, self.atoms._NET_WM_NAME, self.atoms.UTF8_STRING, title.as_bytes()).unwrap();
+            self.conn.change_property32(PropMode::REPLACE, wid, self.atoms._NET_WM_WINDOW_TYPE, AtomEnum::ATOM, &[type_]).unwrap();
+            self.conn.change_property32(PropMode::REPLACE, wid, self.atoms._NET_WM_PID, AtomEnum::CARDINAL, &[pid]).unwrap();
+            wid
+        }
+
+        fn manage(&self, windows: &[Window], active: Window) {
+            self.conn.change_property32(PropMode::REPLACE, self.root, self.atoms._NET_CLIENT_LIST, AtomEnum::WINDOW, windows).unwrap();
+            self.conn.change_property32(PropMode::REPLACE, self.root, self.atoms._NET_ACTIVE_WINDOW, AtomEnum::WINDOW, &[active]).unwrap();
+            self.conn.flush().unwrap();
+        }
+    }
+
+    #[test]
+    fn windows() {
+        let xvfb = match Xvfb::start() {
+            Some(xvfb) => xvfb,
+            None => return,
+        };
+        let fake = Fake::connect(&xvfb.display);
+        let normal = fake.atoms._NET_WM_WINDOW_TYPE_NORMAL;
+        let terminal = fake.window("xterm\0XTerm\0", "vim — ~/notes", normal, 1234);
+        let browser = fake.window("Navigator\0firefox\0", "Example — Mozilla Firefox", normal, 5678);
+        let dock = fake.window("xfce4-panel\0Xfce4-panel\0", "xfce4-panel", fake.atom("_NET_WM_WINDOW_TYPE_DOCK"), 42);
+        fake.manage(&[terminal, browser, dock], browser);
+
+        let x11 = X11::connect(Some(&xvfb.display)).unwrap();
+        assert_eq!(x11.window_manager().unwrap(), None);
+        let check = fake.window("", "Fake WM", normal, 1);
+        fake.conn.change_property32(PropMode::REPLACE, fake.root, fake.atoms._NET_SUPPORTING_WM_CHECK, AtomEnum::WINDOW, &[check]).unwrap();
+        fake.conn.flush().unwrap();
+        assert_eq!(x11.window_manager().unwrap().as_deref(), Some("Fake WM"));
+
+        assert_eq!(x11.get_all_windows().unwrap(), vec![terminal, browser, dock]);
+        assert_eq!(x11.get_active_window().unwrap(), browser);
+
+        let info = x11.get_window_info(terminal).unwrap().unwrap();
+        assert_eq!(info.program, "XTerm");
+        assert_eq!(info.title, "vim — ~/notes");
+        assert_eq!(x11.get_window_info(browser).unwrap().unwrap().program, "firefox");
+        assert!(x11.get_window_info(dock).unwrap().is_none());
+        assert_eq!(info.pid, Some(1234));
+
+        fake.conn.destroy_window(terminal).unwrap();
+        // round trip, so the window is gone before x11 asks about it
+        fake.conn.get_input_focus().unwrap().reply().unwrap();
+        assert!(x11.get_window_info(terminal).unwrap().is_none());
+        assert_eq!(x11.get_window_pid(terminal).unwrap(), None);
+    }
+}
@@ -0,0 +1,459 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use monitor::http::{self, Add, Device, DeviceData, DeviceID, Encoding, Hello};
+use reqwest::StatusCode;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::backoff::Backoff;
+use crate::identity;
+use crate::log;
+
+/// Batches that couldn't be sent, one file each, kept until the server is reachable again.
+pub struct UploadQueue {
+    dir: PathBuf,
+    /// Largest total size in bytes. The oldest batches are dropped beyond this.
+    limit: u64,
+}
+
+impl UploadQueue {
+    pub fn open(dir: &Path, limit: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(UploadQueue { dir: dir.to_owned(), limit })
+    }
+
+    /// Queued files, oldest first.
+    fn entries(&self) -> io::Result<Vec<PathBuf>> {
+        let mut entries: Vec<_> = fs::read_dir(&self.dir)?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.extension() == Some("json".as_ref()))
+            .collect();
+        entries.sort();
+        Ok(entries)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries().map(|entries| entries.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Appends `batch`, dropping the oldest batches if the queue grows beyond its limit.
+    pub fn push(&self, batch: &Add) -> Result<(), Box<dyn Error>> {
+        let entries = self.entries()?;
+        let next = match entries.last().and_then(|path| path.file_stem()) {
+            Some(stem) => stem.to_string_lossy().parse::<u64>()? + 1,
+            None => 0,
+        };
+        // written under another name first, so a crash never leaves half a batch in the queue
+        let path = self.dir.join(format!("{:010}.json", next));
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(batch)?)?;
+        fs::rename(&tmp, &path)?;
+
+        let mut entries = self.entries()?;
+        let mut size: u64 = entries.iter().filter_map(|path| fs::metadata(path).ok()).map(|meta| meta.len()).sum();
+        let mut dropped = 0;
+        while size > self.limit && entries.len() > 1 {
+            let oldest = entries.remove(0);
+            size -= fs::metadata(&oldest).map(|meta| meta.len()).unwrap_or(0);
+            fs::remove_file(&oldest)?;
+            dropped += 1;
+        }
+        if dropped > 0 {
+            log(1, format!("upload queue is full, dropped {} old batches", dropped));
+        }
+        Ok(())
+    }
+
+    /// The oldest batch and its file. Unreadable batches are dropped.
+    pub fn front(&self) -> Result<Option<(PathBuf, Add)>, Box<dyn Error>> {
+        for path in self.entries()? {
+            match fs::read(&path).map_err(Box::<dyn Error>::from).and_then(|bytes| Ok(serde_json::from_slice(&bytes)?)) {
+                Ok(batch) => return Ok(Some((path, batch))),
+                Err(e) => {
+                    log(2, format!("dropping unreadable queued batch {}: {}", path.display(), e));
+                    fs::remove_file(&path)?;
+                },
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+}
+
+/// Something for the server, see `Uploader::run`.
+pub enum Upload {
+    Batch(Add),
+    Device(DeviceData),
+}
+
+/// The server speaks a protocol this client can't, which retrying won't change.
+#[derive(Debug)]
+pub struct Incompatible(String);
+
+impl fmt::Display for Incompatible {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Incompatible {}
+
+/// What was agreed with the server on first contact.
+#[derive(Clone, Copy)]
+struct Session {
+    encoding: Encoding,
+    /// Whether the server replies with `AddReply`.
+    acks: bool,
+    device: DeviceID,
+}
+
+/// Sends batches and device reports to the server, queueing batches while it can't be reached.
+///
+/// The server is only greeted, and the device registered, before the first request, so
+/// nothing waits for the server to be reachable.
+pub struct Uploader {
+    client: reqwest::Client,
+    server: String,
+    name: String,
+    /// Whether to send CBOR if the server supports it.
+    cbor: bool,
+    /// The device id if it is known, otherwise the device registers with `register`.
+    device: Option<DeviceID>,
+    register: http::Register,
+    session: Option<Session>,
+    /// Set once the server turns out to be unusable, which stops the uploader.
+    incompatible: Option<Incompatible>,
+    pub queue: UploadQueue,
+    pub backoff: Backoff,
+}
+
+impl Uploader {
+    pub fn new(client: reqwest::Client, server: &str, name: &str, cbor: bool, device: Option<DeviceID>, register: http::Register, queue: UploadQueue) -> Self {
+        Uploader {
+            client,
+            server: server.to_owned(),
+            name: name.to_owned(),
+            cbor,
+            device,
+            register,
+            session: None,
+            incompatible: None,
+            queue,
+            backoff: Backoff::new(Duration::from_secs(5), Duration::from_secs(300)),
+        }
+    }
+
+    /// Sends everything that arrives on `uploads`, until it closes or the server turns out
+    /// to be incompatible. Batches queued by then stay queued for a newer client.
+    pub async fn run(mut self, mut uploads: UnboundedReceiver<Upload>) -> Result<(), Incompatible> {
+        while let Some(upload) = uploads.recv().await {
+            match upload {
+                Upload::Batch(batch) => {
+                    self.upload(batch).await;
+                },
+                Upload::Device(data) => self.report_device(data).await,
+            }
+            if let Some(e) = self.incompatible.take() {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Greets the server and gets a device id, the first time it is called.
+    async fn session(&mut self) -> Result<Session, Box<dyn Error>> {
+        if let Some(session) = self.session {
+            return Ok(session);
+        }
+
+        let hello = self.client.post(format!("{}/api/hello", self.server))
+            .json(&Hello::new(http::MIN_SERVER_VERSION))
+            .send().await?
+            .json::<Hello>().await
+            // servers before version 2 have no /api/hello
+            .unwrap_or(Hello { version: 1, min_version: 1, features: Vec::new() });
+        hello.check(http::MIN_SERVER_VERSION).map_err(|e| Incompatible(format!("can't use server {}: {}", self.server, e)))?;
+        log(0, format!("server protocol version: {}", hello.version));
+        for feature in http::FEATURES {
+            if !hello.supports(feature) {
+                log(1, format!("server doesn't support {}", feature));
+            }
+        }
+
+        let device = match self.device {
+            Some(id) => id,
+            None if hello.supports("register") => {
+                let registered: http::Registered = self.client.post(format!("{}/api/{}/register", self.server, self.name))
+                    .json(&self.register)
+                    .send().await?
+                    .error_for_status()?
+                    .json().await?;
+                if let Err(e) = identity::save_registered_id(&identity::state_dir(), &self.server, &self.name, registered.id) {
+                    log(1, format!("can't save device id: {}", e));
+                }
+                registered.id
+            },
+            None => self.register.legacy_id.ok_or("couldn't determine device id, use --device-id")?,
+        };
+        log(0, format!("device id: {}", device));
+
+        let encoding = if self.cbor && hello.supports("cbor") { Encoding::Cbor } else { Encoding::Json };
+        let session = Session { encoding, acks: hello.supports("seq"), device };
+        self.session = Some(session);
+        Ok(session)
+    }
+
+    fn succeeded(&mut self) {
+        if self.backoff.succeed() {
+            log(0, format!("server is reachable again, {} batches queued", self.queue.len()));
+        }
+    }
+
+    fn failed(&mut self, e: Box<dyn Error>) {
+        let e = match e.downcast::<Incompatible>() {
+            Ok(incompatible) => {
+                self.incompatible = Some(*incompatible);
+                return;
+            },
+            Err(e) => e,
+        };
+        let online = self.backoff.online();
+        let delay = self.backoff.fail();
+        if online {
+            log(1, format!("server is unreachable, queueing batches and retrying in {}s: {}", delay.as_secs(), e));
+        } else {
+            log(0, format!("server is still unreachable, retrying in {}s: {}", delay.as_secs(), e));
+        }
+    }
+
+    /// Sends `batch` as this device's.
+    async fn send(&mut self, batch: &mut Add) -> Result<(), Box<dyn Error>> {
+        let session = self.session().await?;
+        batch.device = session.device;
+        let body = session.encoding.encode(&*batch)?;
+        let response = self.client.post(format!("{}/api/{}/add", self.server, self.name))
+            .header("content-type", session.encoding.content_type())
+            .body(body)
+            .send().await?;
+        // retrying won't help with a batch the server can't read, so it is given up,
+        // while other client errors like 429 may pass and are retried
+        if [StatusCode::BAD_REQUEST, StatusCode::UNPROCESSABLE_ENTITY].contains(&response.status()) {
+            log(2, format!("server rejected batch {:?}: {}", batch.seq, response.status()));
+            return Ok(());
+        }
+        let response = response.error_for_status()?;
+        if session.acks {
+            let reply: http::AddReply = response.json().await?;
+            match (reply.acked, batch.seq) {
+                (Some(acked), Some(seq)) if acked >= seq => {},
+                (acked, seq) => log(1, format!("batch {:?} not acknowledged, server is at {:?}", seq, acked)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends `batch` after any queued batches, in order, since the server ignores
+    /// batches older than the last one it got. While backing off, `batch` is only queued.
+    /// Returns whether everything was sent.
+    pub async fn upload(&mut self, mut batch: Add) -> bool {
+        if self.queue.is_empty() && self.backoff.ready() {
+            match self.send(&mut batch).await {
+                Ok(()) => {
+                    self.succeeded();
+                    return true;
+                },
+                Err(e) => self.failed(e),
+            }
+        }
+        if let Err(e) = self.queue.push(&batch) {
+            log(2, format!("can't queue batch {:?}, it is lost: {}", batch.seq, e));
+        }
+        if self.incompatible.is_some() || !self.backoff.ready() {
+            return false;
+        }
+        self.drain().await
+    }
+
+    /// Sends queued batches until one fails. Returns whether the queue is empty.
+    async fn drain(&mut self) -> bool {
+        loop {
+            let (path, mut batch) = match self.queue.front() {
+                Ok(Some(front)) => front,
+                Ok(None) => return true,
+                Err(e) => {
+                    log(2, format!("can't read upload queue: {}", e));
+                    return false;
+                },
+            };
+            if let Err(e) = self.send(&mut batch).await {
+                self.failed(e);
+                return false;
+            }
+            self.succeeded();
+            if let Err(e) = self.queue.remove(&path) {
+                log(2, format!("can't remove sent batch from the queue: {}", e));
+                return false;
+            }
+        }
+    }
+
+    /// Sends `data` unless backing off. Device reports aren't queued, the next one replaces them.
+    pub async fn report_device(&mut self, data: DeviceData) {
+        if !self.backoff.ready() {
+            return;
+        }
+        let result = async {
+            let session = self.session().await?;
+            let body = session.encoding.encode(&Device { id: session.device, data })?;
+            self.client.post(format!("{}/api/{}/device", self.server, self.name))
+                .header("content-type", session.encoding.content_type())
+                .body(body)
+                .send().await?
+                .error_for_status()?;
+            Ok::<_, Box<dyn Error>>(())
+        }.await;
+        match result {
+            Ok(()) => self.succeeded(),
+            Err(e) => self.failed(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn batch(seq: u64) -> Add {
+        let mut batch = Add::new(1);
+        batch.client = Some("test".to_owned());
+        batch.seq = Some(seq);
+        batch
+    }
+
+    fn queued_seqs(queue: &UploadQueue) -> Vec<u64> {
+        queue.entries().unwrap().iter()
+            .map(|path| serde_json::from_slice::<Add>(&fs::read(path).unwrap()).unwrap().seq.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let size = serde_json::to_vec(&batch(1)).unwrap().len() as u64;
+        let queue = UploadQueue::open(dir.path(), size * 3).unwrap();
+        for seq in 1..=5 {
+            queue.push(&batch(seq)).unwrap();
+        }
+        assert_eq!(queued_seqs(&queue), vec![3, 4, 5]);
+
+        let (path, front) = queue.front().unwrap().unwrap();
+        assert_eq!(front.seq, Some(3));
+        queue.remove(&path).unwrap();
+        assert_eq!(queue.len(), 2);
+    }
+
+    /// Accepts one request per connection and replies to it like `/api/hello` or `/api/<name>/add`,
+    /// with the status `status` gives for the batch.
+    async fn serve(listener: TcpListener, received: Arc<Mutex<Vec<u64>>>, status: impl Fn(u64) -> u16) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            let body = loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).into_owned();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let len: usize = text[..end].lines()
+                        .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|len| len.trim().parse().unwrap()))
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + len {
+                        break request[end + 4..end + 4 + len].to_vec();
+                    }
+                }
+            };
+            let (status, reply) = if request.starts_with(b"POST /api/hello ") {
+                (200, serde_json::to_string(&Hello::new(1)).unwrap())
+            } else {
+                let batch: Add = serde_json::from_slice(&body).unwrap();
+                received.lock().unwrap().push(batch.seq.unwrap());
+                (status(batch.seq.unwrap()), serde_json::to_string(&http::AddReply { acked: batch.seq }).unwrap())
+            };
+            let response = format!("HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, reply.len(), reply);
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn server_down_and_up() {
+        let dir = tempfile::tempdir().unwrap();
+        // nothing listens on the port once the listener is gone
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let queue = UploadQueue::open(dir.path(), 1 << 20).unwrap();
+        let register = http::Register { machine: "test".to_owned(), legacy_id: None };
+        let mut uploader = Uploader::new(reqwest::Client::new(), &format!("http://{}", addr), "test", false, Some(1), register, queue);
+
+        assert!(!uploader.upload(batch(1)).await);
+        // backing off, so this isn't even tried
+        assert!(!uploader.upload(batch(2)).await);
+        assert!(!uploader.backoff.online());
+        assert_eq!(queued_seqs(&uploader.queue), vec![1, 2]);
+        uploader.backoff = Backoff::new(Duration::from_secs(0), Duration::from_secs(0));
+        assert!(!uploader.upload(batch(3)).await);
+        assert_eq!(queued_seqs(&uploader.queue), vec![1, 2, 3]);
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(serve(TcpListener::bind(addr).await.unwrap(), received.clone(), |_| 200));
+
+        assert!(uploader.upload(batch(4)).await);
+        assert_eq!(*received.lock().unwrap(), vec![1, 2, 3, 4]);
+        assert!(uploader.queue.is_empty());
+        assert!(uploader.backoff.online());
+
+        assert!(uploader.upload(batch(5)).await);
+        assert_eq!(*received.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn rejected_batches() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        // the first batch can't be read, and the second is turned away once
+        let statuses = Mutex::new(vec![(1, 422), (2, 429)]);
+        tokio::spawn(serve(listener, received.clone(), move |seq| {
+            let mut statuses = statuses.lock().unwrap();
+            let status = statuses.iter().position(|&(s, _)| s == seq).map(|i| statuses.remove(i).1);
+            status.unwrap_or(200)
+        }));
+        let queue = UploadQueue::open(dir.path(), 1 << 20).unwrap();
+        let register = http::Register { machine: "test".to_owned(), legacy_id: None };
+        let mut uploader = Uploader::new(reqwest::Client::new(), &url, "test", false, Some(1), register, queue);
+
+        assert!(uploader.upload(batch(1)).await);
+        assert!(uploader.queue.is_empty());
+        assert!(!uploader.upload(batch(2)).await);
+        assert_eq!(queued_seqs(&uploader.queue), vec![2]);
+        assert!(!uploader.backoff.online());
+
+        uploader.backoff = Backoff::new(Duration::from_secs(0), Duration::from_secs(0));
+        assert!(uploader.upload(batch(3)).await);
+        assert_eq!(*received.lock().unwrap(), vec![1, 2, 2, 3]);
+        assert!(uploader.queue.is_empty());
+    }
+}
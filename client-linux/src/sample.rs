@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use monitor::http::{Add, Interval};
+use monitor::names::{AppName, AppNames};
+use monitor::privacy::Privacy;
+use monitor::rules::RuleSet;
+use monitor::source::{WindowSource, Windows};
+use monitor::{ActiveProgram, RawWindowData};
+
+use crate::focus::{Change, FocusTimer};
+use crate::log;
+use crate::process::WindowInfo;
+use crate::terminal;
+
+/// Seconds between ticks while window changes are watched, as ticks then only check
+/// whether the user is locked or idle.
+pub const WATCHED_TICK: u32 = 5;
+
+/// Turns windows into data. Active time comes from the focus intervals, the rest is
+/// counted one tick at a time.
+pub struct Sampler {
+    pub rules: RuleSet,
+    pub privacy: Privacy,
+    pub names: AppNames,
+    pub idle_threshold: Duration,
+    /// Seconds counted per tick.
+    pub tick: u32,
+    /// The open programs and the focused window classified, as of the last windows.
+    open: Vec<AppName>,
+    focused: Option<(ActiveProgram, Option<String>)>,
+    timer: FocusTimer,
+    /// Document of the current interval.
+    document: Option<String>,
+    /// Focused milliseconds not yet counted as a whole second.
+    millis: HashMap<(ActiveProgram, Option<String>), u64>,
+    /// Whether the last tick was locked or idle.
+    away: bool,
+}
+
+impl Sampler {
+    pub fn new(rules: RuleSet, privacy: Privacy, names: AppNames, idle_threshold: Duration, tick: u32) -> Self {
+        Sampler {
+            rules, privacy, names, idle_threshold, tick,
+            open: Vec::new(), focused: None, timer: FocusTimer::default(), document: None, millis: HashMap::new(), away: false,
+        }
+    }
+
+    /// Takes the windows from a change, ending the current interval at the time of the change.
+    pub fn change(&mut self, http_data: &mut Add, change: Change) {
+        self.set_windows(change.windows);
+        if !self.away {
+            self.focus(http_data, self.focused.clone(), change.at);
+        }
+    }
+
+    /// Adds one tick of data at `now`. The windows are read from `source` if `poll`,
+    /// otherwise those of the last change are used.
+    /// Returns false if the session is locked, in which case nothing is counted.
+    pub fn tick(&mut self, source: &dyn WindowSource<Window = WindowInfo>, http_data: &mut Add, poll: bool, now: u64) -> bool {
+        if poll {
+            match source.windows() {
+                Ok(windows) => self.set_windows(windows),
+                Err(e) => log(2, e.to_string()),
+            }
+        }
+
+        // Skip counting if session is locked (i.e. user isn't using the computer)
+        let locked = source.is_locked().unwrap_or_else(|e| {
+            log(1, format!("error checking lock state: {}", e));
+            false
+        });
+        if locked {
+            self.away = true;
+            self.focus(http_data, None, now);
+            return false;
+        }
+
+        let idle = match source.idle_time() {
+            Ok(Some(time)) => time >= self.idle_threshold,
+            Ok(None) => false,
+            Err(e) => {
+                log(1, format!("error reading idle time: {}", e));
+                false
+            },
+        };
+        if idle {
+            http_data.idle += self.tick;
+        }
+        self.away = idle;
+
+        for app in &self.open {
+            http_data.program_ids.insert(app.name.clone(), app.id.clone());
+            *http_data.open.entry(monitor::Program { program: app.name.clone() }).or_insert(0) += self.tick;
+        }
+        let focused = if idle { None } else { self.focused.clone() };
+        self.focus(http_data, focused, now);
+        true
+    }
+
+    /// Ends the intervals in `http_data` at `now`, before it is sent.
+    pub fn flush(&mut self, http_data: &mut Add, now: u64) {
+        let ended = self.timer.flush(&mut http_data.intervals, now);
+        self.count(http_data, ended);
+    }
+
+    /// Names and classifies the windows, which is only done when they change.
+    fn set_windows(&mut self, windows: Windows<WindowInfo>) {
+        self.open = windows.windows.iter()
+            .map(|(_, data)| self.names.resolve(&self.privacy.window(data).program()))
+            .collect();
+        self.focused = self.classify_active(&windows);
+    }
+
+    /// Ends the current interval at `at`, counting it, and starts one for `focused`.
+    /// `None` means nothing is in use.
+    fn focus(&mut self, http_data: &mut Add, focused: Option<(ActiveProgram, Option<String>)>, at: u64) {
+        let ended = self.timer.flush(&mut http_data.intervals, at);
+        self.count(http_data, ended);
+        let (program, document) = match focused {
+            Some((program, document)) => (Some(program), document),
+            None => (None, None),
+        };
+        self.timer.switch(&mut http_data.intervals, program, at);
+        self.document = document;
+    }
+
+    /// Counts the whole seconds of an interval as active, and of its document if there is one.
+    fn count(&mut self, http_data: &mut Add, interval: Option<Interval>) {
+        let interval = match interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let key = (interval.program, self.document.clone());
+        let millis = self.millis.entry(key.clone()).or_insert(0);
+        *millis += interval.end - interval.start;
+        let secs = (*millis / 1000) as u32;
+        if secs == 0 {
+            return;
+        }
+        *millis %= 1000;
+
+        let (active, document) = key;
+        if let Some(document) = document {
+            *http_data.documents.entry(active.clone()).or_default().entry(document).or_insert(0) += secs;
+        }
+        *http_data.active.entry(active).or_insert(0) += secs;
+    }
+
+    /// The focused window classified, and the document open in it.
+    fn classify_active(&self, windows: &Windows<WindowInfo>) -> Option<(ActiveProgram, Option<String>)> {
+        let mut data = windows.active_window()?.clone();
+        if terminal::is_terminal(&data.program) {
+            if let Some(pid) = data.pid {
+                data.command = terminal::ProcFs::default().foreground_command(pid);
+            }
+        }
+        let data = self.privacy.window(&data);
+        let mut active = self.privacy.active(self.rules.classify(&data));
+        active.program = self.names.resolve(&data.program()).name;
+        let document = self.rules.document(&data).map(|document| self.privacy.detail(document));
+        Some((active, document))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use monitor::source::{Frame, Scripted};
+
+    fn window(program: &str, title: &str) -> WindowInfo {
+        WindowInfo { program: program.to_owned(), title: title.to_owned(), command: None, pid: None }
+    }
+
+    fn active(program: &str, subprogram: Option<&str>) -> ActiveProgram {
+        ActiveProgram { program: program.to_owned(), subprogram: subprogram.map(str::to_owned) }
+    }
+
+    fn sampler() -> Sampler {
+        Sampler::new(RuleSet::defaults().clone(), Privacy::default(), AppNames::default(), Duration::from_secs(300), 1)
+    }
+
+    #[test]
+    fn polling() {
+        let windows = vec![
+            (1, window("firefox", "Watch - YouTube — Mozilla Firefox")),
+            (2, window("code", "main.rs - monitor - Visual Studio Code")),
+        ];
+        let mut idle = Frame::new(Some(2), windows.clone());
+        idle.idle = Duration::from_secs(600);
+        let mut locked = Frame::new(Some(2), windows.clone());
+        locked.locked = true;
+        let source = Scripted::new(vec![
+            Frame::new(Some(1), windows.clone()),
+            Frame::new(Some(1), windows.clone()),
+            Frame::new(Some(2), windows.clone()),
+            idle,
+            locked,
+        ]);
+
+        let mut sampler = sampler();
+        let mut http_data = Add::new(1);
+        let mut now = 10_000;
+        let mut counted = Vec::new();
+        loop {
+            counted.push(sampler.tick(&source, &mut http_data, true, now));
+            now += 1000;
+            if !source.advance() {
+                break;
+            }
+        }
+        sampler.flush(&mut http_data, now);
+
+        assert_eq!(counted, vec![true, true, true, true, false]);
+        let youtube = active("Firefox", Some("youtube.com"));
+        let code = active("Code", Some("monitor"));
+        assert_eq!(http_data.active.len(), 2);
+        assert_eq!(http_data.active[&youtube], 2);
+        assert_eq!(http_data.active[&code], 1);
+        assert_eq!(http_data.open[&monitor::Program { program: "Firefox".to_owned() }], 4);
+        assert_eq!(http_data.idle, 1);
+        let intervals: Vec<_> = http_data.intervals.iter().map(|i| (i.program.clone(), i.start, i.end)).collect();
+        assert_eq!(intervals, vec![(youtube, 10_000, 12_000), (code, 12_000, 13_000)]);
+    }
+
+    #[test]
+    fn changes() {
+        let windows = vec![
+            (1, window("firefox", "Watch - YouTube — Mozilla Firefox")),
+            (2, window("code", "main.rs - monitor - Visual Studio Code")),
+        ];
+        // ticks don't read the windows, or Code would be counted
+        let source = Scripted::new(vec![Frame::new(Some(2), windows.clone())]);
+        let mut sampler = sampler();
+        let mut http_data = Add::new(1);
+
+        sampler.change(&mut http_data, Change { at: 10_000, windows: Windows { active: Some(1), windows: windows.clone() } });
+        assert!(sampler.tick(&source, &mut http_data, false, 12_500));
+        sampler.change(&mut http_data, Change { at: 13_000, windows: Windows { active: Some(2), windows: windows.clone() } });
+        sampler.change(&mut http_data, Change { at: 13_600, windows: Windows { active: Some(1), windows } });
+        assert!(sampler.tick(&source, &mut http_data, false, 17_500));
+        sampler.flush(&mut http_data, 18_000);
+
+        let youtube = active("Firefox", Some("youtube.com"));
+        let code = active("Code", Some("monitor"));
+        // 7.4 seconds on YouTube, and the 0.6 on Code isn't a whole second yet
+        assert_eq!(http_data.active[&youtube], 7);
+        assert!(!http_data.active.contains_key(&code));
+        assert_eq!(http_data.open[&monitor::Program { program: "Code".to_owned() }], 2);
+        let intervals: Vec<_> = http_data.intervals.iter().map(|i| (i.program.clone(), i.start, i.end)).collect();
+        assert_eq!(intervals, vec![(youtube.clone(), 10_000, 13_000), (code.clone(), 13_000, 13_600), (youtube, 13_600, 18_000)]);
+
+        // the rest is carried over to the next batch
+        let mut http_data = Add::new(1);
+        sampler.change(&mut http_data, Change { at: 18_000, windows: Windows { active: Some(2), windows: source.windows().unwrap().windows } });
+        sampler.flush(&mut http_data, 18_400);
+        assert_eq!(http_data.active[&code], 1);
+    }
+}
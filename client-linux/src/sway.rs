@@ -0,0 +1,308 @@
+use std::error::Error;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use serde::Deserialize;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use monitor::source::{WindowSource, Windows};
+
+use crate::focus::Change;
+use crate::idle::IdleMonitor;
+use crate::lock::LockDetector;
+use crate::log;
+use crate::process::WindowInfo;
+
+const MAGIC: &[u8] = b"i3-ipc";
+const SUBSCRIBE: u32 = 2;
+const GET_TREE: u32 = 4;
+const GET_VERSION: u32 = 7;
+/// Replies have the type of the request, events have this bit set.
+const EVENT: u32 = 1 << 31;
+
+/// Reads windows from sway or i3 over their IPC socket.
+pub struct Sway {
+    path: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Node {
+    /// A pointer in i3, so it needs 64 bits.
+    id: u64,
+    #[serde(rename = "type")]
+    type_: String,
+    name: Option<String>,
+    /// Set for Wayland windows.
+    app_id: Option<String>,
+    /// Set for X11 windows.
+    window_properties: Option<WindowProperties>,
+    pid: Option<u32>,
+    focused: bool,
+    nodes: Vec<Node>,
+    floating_nodes: Vec<Node>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct WindowProperties {
+    class: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Version {
+    /// Only sent by sway.
+    variant: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Event {
+    change: String,
+}
+
+impl Node {
+    fn is_window(&self) -> bool {
+        (self.type_ == "con" || self.type_ == "floating_con") && self.nodes.is_empty() && self.floating_nodes.is_empty()
+    }
+
+    fn collect(&self, windows: &mut Windows<WindowInfo>) {
+        if self.is_window() {
+            let program = self.app_id.clone()
+                .or_else(|| self.window_properties.as_ref().and_then(|props| props.class.clone()))
+                .unwrap_or_default();
+            let title = self.name.clone().unwrap_or_default();
+            windows.windows.push((self.id, WindowInfo { program, title, command: None, pid: self.pid }));
+            if self.focused {
+                windows.active = Some(self.id);
+            }
+        }
+        for node in self.nodes.iter().chain(&self.floating_nodes) {
+            node.collect(windows);
+        }
+    }
+}
+
+fn send(stream: &mut UnixStream, type_: u32, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut message = MAGIC.to_vec();
+    message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    message.extend_from_slice(&type_.to_ne_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)?;
+    Ok(())
+}
+
+fn receive(stream: &mut UnixStream) -> Result<(u32, Vec<u8>), Box<dyn Error>> {
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header)?;
+    if &header[..6] != MAGIC {
+        return Err("not an i3 IPC message".into());
+    }
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&header[6..10]);
+    let mut type_ = [0u8; 4];
+    type_.copy_from_slice(&header[10..14]);
+    let mut payload = vec![0u8; u32::from_ne_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((u32::from_ne_bytes(type_), payload))
+}
+
+impl Sway {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Sway { path: path.into() }
+    }
+
+    /// The socket from `$SWAYSOCK` or `$I3SOCK`, if either is set.
+    pub fn from_env() -> Option<Self> {
+        std::env::var_os("SWAYSOCK").or_else(|| std::env::var_os("I3SOCK"))
+            .filter(|path| !path.is_empty())
+            .map(Sway::new)
+    }
+
+    fn windows_from(stream: &mut UnixStream) -> Result<Windows<WindowInfo>, Box<dyn Error>> {
+        send(stream, GET_TREE, b"")?;
+        loop {
+            let (type_, payload) = receive(stream)?;
+            if type_ == GET_TREE {
+                let tree: Node = serde_json::from_slice(&payload)?;
+                let mut windows = Windows::default();
+                tree.collect(&mut windows);
+                return Ok(windows);
+            }
+        }
+    }
+
+    pub fn windows(&self) -> Result<Windows<WindowInfo>, Box<dyn Error>> {
+        Sway::windows_from(&mut UnixStream::connect(&self.path)?)
+    }
+
+    /// "sway" or "i3", whichever is on the other end of the socket.
+    pub fn window_manager(&self) -> Result<String, Box<dyn Error>> {
+        let mut stream = UnixStream::connect(&self.path)?;
+        send(&mut stream, GET_VERSION, b"")?;
+        loop {
+            let (type_, payload) = receive(&mut stream)?;
+            if type_ == GET_VERSION {
+                let version: Version = serde_json::from_slice(&payload)?;
+                return Ok(version.variant.unwrap_or_else(|| "i3".to_owned()));
+            }
+        }
+    }
+
+    /// Watches for window and workspace events in a thread of its own. The channel closes if that fails.
+    pub fn watch(&self) -> Result<UnboundedReceiver<Change>, Box<dyn Error>> {
+        let mut events = UnixStream::connect(&self.path)?;
+        send(&mut events, SUBSCRIBE, br#"["window","workspace"]"#)?;
+        let (_, reply) = receive(&mut events)?;
+        if !String::from_utf8_lossy(&reply).contains("true") {
+            return Err(format!("can't subscribe to sway events: {}", String::from_utf8_lossy(&reply)).into());
+        }
+        // events may arrive between the request and reply on the events socket, so the tree is read on another
+        let requests = UnixStream::connect(&self.path)?;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            if let Err(e) = watch_changes(events, requests, &sender) {
+                log(1, format!("stopped watching windows: {}", e));
+            }
+        });
+        Ok(receiver)
+    }
+}
+
+/// `WindowSource` for sway and i3 sessions. Idle time isn't available over IPC, so it comes from X11 if at all.
+pub struct SwaySource {
+    pub sway: Sway,
+    /// i3 runs on X11, so its idle time can be read from there.
+    pub idle: Option<IdleMonitor>,
+    pub locker: Option<Box<dyn LockDetector>>,
+}
+
+impl WindowSource for SwaySource {
+    type Window = WindowInfo;
+
+    fn active_window(&self) -> Result<Option<u64>, Box<dyn Error>> {
+        Ok(self.sway.windows()?.active)
+    }
+
+    fn window_list(&self) -> Result<Vec<u64>, Box<dyn Error>> {
+        Ok(self.sway.windows()?.windows.into_iter().map(|(id, _)| id).collect())
+    }
+
+    fn window_info(&self, id: u64) -> Result<Option<WindowInfo>, Box<dyn Error>> {
+        Ok(self.sway.windows()?.windows.into_iter().find(|(window, _)| *window == id).map(|(_, info)| info))
+    }
+
+    fn windows(&self) -> Result<Windows<WindowInfo>, Box<dyn Error>> {
+        self.sway.windows()
+    }
+
+    fn is_locked(&self) -> Result<bool, Box<dyn Error>> {
+        match &self.locker {
+            Some(locker) => locker.is_locked(),
+            None => Ok(false),
+        }
+    }
+
+    fn idle_time(&self) -> Result<Option<std::time::Duration>, Box<dyn Error>> {
+        match &self.idle {
+            Some(idle) => Ok(Some(idle.idle_time()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn watch_changes(mut events: UnixStream, mut requests: UnixStream, sender: &UnboundedSender<Change>) -> Result<(), Box<dyn Error>> {
+    loop {
+        let (type_, payload) = receive(&mut events)?;
+        if type_ & EVENT == 0 {
+            continue;
+        }
+        let event: Event = serde_json::from_slice(&payload)?;
+        if !["focus", "title", "new", "close", "move"].contains(&event.change.as_str()) {
+            continue;
+        }
+        let at = monitor::http::unix_millis();
+        let windows = Sway::windows_from(&mut requests)?;
+        if sender.send(Change { at, windows }).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    const TREE: &str = r#"{"id": 1, "type": "root", "name": "root", "focused": false, "nodes": [
+        {"id": 2, "type": "output", "name": "eDP-1", "nodes": [
+            {"id": 3, "type": "workspace", "name": "1", "nodes": [
+                {"id": 4, "type": "con", "name": null, "nodes": [
+                    {"id": 5, "type": "con", "name": "notes.md - Visual Studio Code", "app_id": null,
+                     "window_properties": {"class": "Code", "instance": "code"}, "pid": 100, "focused": false, "nodes": []},
+                    {"id": 94372857112576, "type": "con", "name": "vim", "app_id": "foot", "pid": 200, "focused": true, "nodes": []}
+                ]}
+            ], "floating_nodes": [
+                {"id": 7, "type": "floating_con", "name": "Picture-in-Picture", "app_id": "firefox", "pid": 300, "nodes": []}
+            ]},
+            {"id": 8, "type": "workspace", "name": "2", "nodes": [], "floating_nodes": []}
+        ]}
+    ]}"#;
+
+    /// Answers GET_TREE with `TREE`, GET_VERSION like sway, and SUBSCRIBE with success followed by a focus event.
+    fn fake_sway(path: &std::path::Path) {
+        let listener = UnixListener::bind(path).unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    while let Ok((type_, _)) = receive(&mut stream) {
+                        match type_ {
+                            GET_TREE => send(&mut stream, GET_TREE, TREE.as_bytes()).unwrap(),
+                            GET_VERSION => send(&mut stream, GET_VERSION, br#"{"major": 1, "minor": 9, "human_readable": "1.9", "variant": "sway"}"#).unwrap(),
+                            SUBSCRIBE => {
+                                send(&mut stream, SUBSCRIBE, br#"{"success": true}"#).unwrap();
+                                send(&mut stream, EVENT | 3, br#"{"change": "mark", "container": {"id": 94372857112576}}"#).unwrap();
+                                send(&mut stream, EVENT | 3, br#"{"change": "focus", "container": {"id": 94372857112576}}"#).unwrap();
+                            },
+                            _ => {},
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn windows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sway.sock");
+        fake_sway(&path);
+
+        let windows = Sway::new(&path).windows().unwrap();
+        let found: Vec<_> = windows.windows.iter().map(|(id, info)| (*id, info.program.as_str(), info.title.as_str())).collect();
+        assert_eq!(found, vec![
+            (5, "Code", "notes.md - Visual Studio Code"),
+            (94372857112576, "foot", "vim"),
+            (7, "firefox", "Picture-in-Picture"),
+        ]);
+        assert_eq!(windows.active, Some(94372857112576));
+        assert_eq!(windows.active_window().unwrap().pid, Some(200));
+        assert_eq!(Sway::new(&path).window_manager().unwrap(), "sway");
+    }
+
+    #[test]
+    fn events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sway.sock");
+        fake_sway(&path);
+
+        let mut changes = Sway::new(&path).watch().unwrap();
+        let change = changes.blocking_recv().unwrap();
+        assert_eq!(change.windows.active, Some(94372857112576));
+        assert_eq!(change.windows.windows.len(), 3);
+        assert!(changes.try_recv().is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use monitor::http::{DeviceType, Monitor};
+
+fn read_trimmed(path: &str) -> Option<String> {
+    let s = fs::read_to_string(path).ok()?;
+    let s = s.trim();
+    if s.is_empty() { None } else { Some(s.to_owned()) }
+}
+
+pub fn hostname() -> Option<String> {
+    read_trimmed("/proc/sys/kernel/hostname")
+}
+
+pub fn kernel() -> Option<String> {
+    read_trimmed("/proc/sys/kernel/osrelease")
+}
+
+/// CPU model and thread count from `/proc/cpuinfo`.
+fn parse_cpuinfo(cpuinfo: &str) -> Option<String> {
+    let mut model = None;
+    let mut threads = 0;
+    for line in cpuinfo.lines() {
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts.next().unwrap_or("").trim();
+        match key {
+            "processor" => threads += 1,
+            "model name" if model.is_none() => model = Some(value.to_owned()),
+            _ => {},
+        }
+    }
+    Some(format!("{} ({} threads)", model?, threads))
+}
+
+pub fn cpu() -> Option<String> {
+    parse_cpuinfo(&fs::read_to_string("/proc/cpuinfo").ok()?)
+}
+
+/// Total memory in MiB from `/proc/meminfo`.
+fn parse_meminfo(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let kib: u64 = line["MemTotal:".len()..].trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib / 1024)
+}
+
+pub fn memory_mib() -> Option<u64> {
+    parse_meminfo(&fs::read_to_string("/proc/meminfo").ok()?)
+}
+
+/// Monitors from the output of `xrandr --listmonitors`, lines like
+/// ` 0: +*eDP-1 1920/344x1080/194+0+0  eDP-1`.
+fn parse_xrandr_monitors(output: &str) -> Vec<Monitor> {
+    output.lines().skip(1).filter_map(|line| {
+        let geometry = line.split_whitespace().nth(2)?;
+        let mut parts = geometry.split('x');
+        let width = parts.next()?.split('/').next()?.parse().ok()?;
+        let height = parts.next()?.split('/').next()?.parse().ok()?;
+        Some(Monitor { width, height })
+    }).collect()
+}
+
+pub fn monitors() -> Option<Vec<Monitor>> {
+    let output = Command::new("xrandr").arg("--listmonitors").output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(parse_xrandr_monitors(std::str::from_utf8(&output.stdout).ok()?))
+}
+
+pub fn desktop() -> Option<String> {
+    std::env::var("XDG_CURRENT_DESKTOP").ok().filter(|s| !s.is_empty())
+}
+
+/// Device type for an SMBIOS chassis type, see `/sys/class/dmi/id/chassis_type`.
+fn chassis_device_type(chassis: u32) -> Option<DeviceType> {
+    match chassis {
+        3 | 4 | 5 | 6 | 7 | 13 | 15 | 16 | 24 | 35 | 36 => Some(DeviceType::Desktop),
+        8 | 9 | 10 | 14 | 31 => Some(DeviceType::Laptop),
+        30 | 32 => Some(DeviceType::Tablet),
+        _ => None,
+    }
+}
+
+/// Whether a system battery is listed in `power_supply` (batteries of mice etc. have scope "Device").
+fn has_battery(sysfs: &Path) -> bool {
+    let entries = match fs::read_dir(sysfs.join("class/power_supply")) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(Result::ok).any(|entry| {
+        let path = entry.path();
+        let type_ = fs::read_to_string(path.join("type")).unwrap_or_default();
+        let scope = fs::read_to_string(path.join("scope")).unwrap_or_default();
+        type_.trim() == "Battery" && scope.trim() != "Device"
+    })
+}
+
+/// Guesses the device type from the chassis type, or from whether there is a battery
+/// if the chassis type is missing or not meaningful. `sysfs` is normally `/sys`.
+pub fn device_type(sysfs: &Path) -> DeviceType {
+    let chassis = fs::read_to_string(sysfs.join("class/dmi/id/chassis_type")).ok()
+        .and_then(|s| s.trim().parse().ok())
+        .and_then(chassis_device_type);
+    match chassis {
+        Some(type_) => type_,
+        None if has_battery(sysfs) => DeviceType::Laptop,
+        None => DeviceType::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn device_types() {
+        let desktop = tempfile::tempdir().unwrap();
+        write(desktop.path(), "class/dmi/id/chassis_type", "3\n");
+        write(desktop.path(), "class/power_supply/hid-mouse-battery/type", "Battery\n");
+        write(desktop.path(), "class/power_supply/hid-mouse-battery/scope", "Device\n");
+        assert!(matches!(device_type(desktop.path()), DeviceType::Desktop));
+
+        let laptop = tempfile::tempdir().unwrap();
+        write(laptop.path(), "class/dmi/id/chassis_type", "10\n");
+        assert!(matches!(device_type(laptop.path()), DeviceType::Laptop));
+
+        let tablet = tempfile::tempdir().unwrap();
+        write(tablet.path(), "class/dmi/id/chassis_type", "30\n");
+        assert!(matches!(device_type(tablet.path()), DeviceType::Tablet));
+
+        let unknown_with_battery = tempfile::tempdir().unwrap();
+        write(unknown_with_battery.path(), "class/dmi/id/chassis_type", "2\n");
+        write(unknown_with_battery.path(), "class/power_supply/AC/type", "Mains\n");
+        write(unknown_with_battery.path(), "class/power_supply/BAT0/type", "Battery\n");
+        assert!(matches!(device_type(unknown_with_battery.path()), DeviceType::Laptop));
+
+        let empty = tempfile::tempdir().unwrap();
+        assert!(matches!(device_type(empty.path()), DeviceType::Other));
+    }
+
+    #[test]
+    fn cpuinfo() {
+        let cpuinfo = "processor\t: 0\nmodel name\t: Intel(R) Core(TM) i5-8250U CPU @ 1.60GHz\n\nprocessor\t: 1\nmodel name\t: Intel(R) Core(TM) i5-8250U CPU @ 1.60GHz\n";
+        assert_eq!(parse_cpuinfo(cpuinfo).as_deref(), Some("Intel(R) Core(TM) i5-8250U CPU @ 1.60GHz (2 threads)"));
+        assert_eq!(parse_cpuinfo("processor\t: 0\n"), None);
+    }
+
+    #[test]
+    fn meminfo() {
+        assert_eq!(parse_meminfo("MemTotal:       16303276 kB\nMemFree:         1234 kB\n"), Some(15921));
+        assert_eq!(parse_meminfo("MemFree: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn xrandr() {
+        let output = "Monitors: 2\n 0: +*eDP-1 1920/344x1080/194+0+0  eDP-1\n 1: +HDMI-1 2560/597x1440/336+1920+0  HDMI-1\n";
+        assert_eq!(parse_xrandr_monitors(output), vec![
+            Monitor { width: 1920, height: 1080 },
+            Monitor { width: 2560, height: 1440 },
+        ]);
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// WM_CLASS names of terminal emulators, compared case-insensitively.
+const TERMINALS: &[&str] = &[
+    "xfce4-terminal", "gnome-terminal-server", "konsole", "xterm", "uxterm", "urxvt",
+    "alacritty", "kitty", "terminator", "tilix", "st-256color", "wezterm", "terminal",
+];
+
+pub fn is_terminal(program: &str) -> bool {
+    TERMINALS.iter().any(|t| t.eq_ignore_ascii_case(program))
+}
+
+/// Fields of `/proc/<pid>/stat` we care about.
+#[derive(Debug, Clone, PartialEq)]
+struct Stat {
+    pid: u32,
+    ppid: u32,
+    /// Foreground process group of the controlling terminal, -1 if there's none.
+    tpgid: i32,
+}
+
+/// Reads processes from a procfs mount, normally `/proc`.
+pub struct ProcFs {
+    root: PathBuf,
+}
+
+impl Default for ProcFs {
+    fn default() -> Self {
+        ProcFs::new("/proc")
+    }
+}
+
+impl ProcFs {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        ProcFs { root: root.as_ref().to_owned() }
+    }
+
+    fn stat(&self, pid: u32) -> Option<Stat> {
+        let data = fs::read_to_string(self.root.join(pid.to_string()).join("stat")).ok()?;
+        // the command name is in parentheses and may contain spaces
+        let rest = &data[data.rfind(')')? + 1..];
+        let fields: Vec<_> = rest.split_whitespace().collect();
+        // fields are numbered from 1 in proc(5), and `rest` starts at field 3
+        Some(Stat {
+            pid,
+            ppid: fields.get(1)?.parse().ok()?,
+            tpgid: fields.get(5)?.parse().ok()?,
+        })
+    }
+
+    fn comm(&self, pid: u32) -> Option<String> {
+        let comm = fs::read_to_string(self.root.join(pid.to_string()).join("comm")).ok()?;
+        Some(comm.trim_end().to_owned())
+    }
+
+    /// From `task/<tid>/children`, which lists the children each thread started,
+    /// so other processes don't have to be read.
+    fn children(&self, pid: u32) -> Vec<Stat> {
+        let tasks = match fs::read_dir(self.root.join(pid.to_string()).join("task")) {
+            Ok(tasks) => tasks,
+            Err(_) => return Vec::new(),
+        };
+        tasks.filter_map(|task| fs::read_to_string(task.ok()?.path().join("children")).ok())
+            .flat_map(|children| children.split_whitespace().filter_map(|child| child.parse::<u32>().ok()).collect::<Vec<_>>())
+            .filter_map(|child| self.stat(child))
+            .filter(|stat| stat.ppid == pid)
+            .collect()
+    }
+
+    /// Name of the foreground command in a terminal emulator with process id `pid`.
+    ///
+    /// Looks at the terminal's children with a controlling terminal (its shells) and returns
+    /// the foreground process of the shell if it isn't in the foreground itself. Terminals
+    /// like gnome-terminal-server run every window in one process, and nothing ties a shell
+    /// to a window, so there's no answer when there is more than one shell.
+    pub fn foreground_command(&self, pid: u32) -> Option<String> {
+        let mut shells = self.children(pid).into_iter().filter(|child| child.tpgid > 0);
+        let shell = shells.next()?;
+        if shells.next().is_some() || shell.tpgid as u32 == shell.pid {
+            return None;
+        }
+        self.comm(shell.tpgid as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_process(root: &Path, pid: u32, comm: &str, ppid: u32, tpgid: i32) {
+        let dir = root.join(pid.to_string());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("comm"), format!("{}\n", comm)).unwrap();
+        let mut fields = vec!["S".to_owned(), ppid.to_string(), pid.to_string(), pid.to_string(), "34816".to_owned(), tpgid.to_string()];
+        fields.resize(20, "0".to_owned());
+        fs::write(dir.join("stat"), format!("{} ({}) {}\n", pid, comm, fields.join(" "))).unwrap();
+        fs::create_dir_all(dir.join("task").join(pid.to_string())).unwrap();
+
+        let parent = root.join(ppid.to_string()).join("task").join(ppid.to_string());
+        fs::create_dir_all(&parent).unwrap();
+        let mut children = fs::read_to_string(parent.join("children")).unwrap_or_default();
+        children.push_str(&format!("{} ", pid));
+        fs::write(parent.join("children"), children).unwrap();
+    }
+
+    #[test]
+    fn foreground_command() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        add_process(root, 100, "alacritty", 1, -1);
+        add_process(root, 150, "dbus-launch", 100, -1);
+        add_process(root, 300, "bash", 100, 301);
+        add_process(root, 301, "cargo test", 300, 301);
+
+        let proc_fs = ProcFs::new(root);
+        assert_eq!(proc_fs.foreground_command(100).as_deref(), Some("cargo test"));
+        assert_eq!(proc_fs.foreground_command(300), None);
+        assert_eq!(proc_fs.foreground_command(999), None);
+    }
+
+    #[test]
+    fn several_shells() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        add_process(root, 100, "gnome-terminal-server", 1, -1);
+        add_process(root, 200, "bash", 100, 201);
+        add_process(root, 201, "vim", 200, 201);
+        add_process(root, 300, "bash", 100, 301);
+        add_process(root, 301, "cargo test", 300, 301);
+
+        // either window could be the focused one
+        assert_eq!(ProcFs::new(root).foreground_command(100), None);
+    }
+
+    #[test]
+    fn idle_shell() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        add_process(root, 100, "kitty", 1, -1);
+        add_process(root, 200, "zsh", 100, 200);
+
+        assert_eq!(ProcFs::new(root).foreground_command(100), None);
+    }
+
+    #[test]
+    fn terminals() {
+        assert!(is_terminal("Xfce4-terminal"));
+        assert!(!is_terminal("Firefox"));
+    }
+}
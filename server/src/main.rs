@@ -1,10 +1,11 @@
 #[macro_use]
 extern crate lazy_static;
 
-use std::{collections::HashMap, error::Error, sync::Mutex, task::Context};
+use std::{collections::{HashMap, HashSet}, error::Error, sync::Mutex, task::Context};
 
 use chrono::{Datelike, NaiveDate};
 use monitor::http;
+use monitor::category::{Category, CategoryMap};
 use serde_json::json;
 use std::borrow::Borrow;
 use warp::{Filter, Rejection, Reply};
@@ -14,6 +15,16 @@ use serde::{Serialize,Deserialize};
 struct MonitorData {
     active: HashMap<monitor::ActiveProgram, u32>,
     open: HashMap<monitor::Program, u32>,
+    #[serde(default)]
+    documents: HashMap<monitor::ActiveProgram, HashMap<String, u32>>,
+    /// Stable id of each program name.
+    #[serde(default)]
+    program_ids: HashMap<String, String>,
+    #[serde(default)]
+    intervals: Vec<http::Interval>,
+    /// Seconds the user was idle without locking the screen.
+    #[serde(default)]
+    idle: u32,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -22,11 +33,57 @@ struct UserData {
     devices: HashMap<monitor::http::DeviceID, monitor::http::DeviceData>,
     
     
-    monitor: HashMap<monitor::http::DeviceID, MonitorData>
+    monitor: HashMap<monitor::http::DeviceID, MonitorData>,
+
+    /// Last acknowledged batch per client. Kept when the day changes, until the client is gone for `ACK_DAYS`.
+    #[serde(default)]
+    acked: HashMap<String, Acked>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Acked {
+    seq: u64,
+    /// Unix time in milliseconds of the batch.
+    at: u64,
 }
 
+/// Clients restart with a new id, so acks of clients that stopped sending are dropped after this many days.
+const ACK_DAYS: u64 = 3;
+
 lazy_static! {
     static ref STATIC_DATA: Mutex<HashMap<String, UserData>> = Mutex::new(HashMap::new());
+    static ref CATEGORIES: Mutex<CategoryMap> = Mutex::new(CategoryMap::defaults().clone());
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+const REGISTRY_FILE: &str = "devices.json";
+
+/// First id handed out to new machines. Lower ids were derived from IP addresses by older clients.
+const FIRST_REGISTERED_ID: monitor::http::DeviceID = 256;
+
+/// Registered device ids per user and machine.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+struct Registry {
+    users: HashMap<String, HashMap<String, monitor::http::DeviceID>>,
+}
+
+impl Registry {
+    /// Returns the id of a machine and whether it is new, assigning one if it is.
+    /// `None` if every id is taken.
+    fn register(&mut self, name: &str, body: &http::Register) -> Option<(monitor::http::DeviceID, bool)> {
+        let devices = self.users.entry(name.to_owned()).or_default();
+        if let Some(&id) = devices.get(&body.machine) {
+            return Some((id, false));
+        }
+        let taken: HashSet<_> = devices.values().copied().collect();
+        let id = match body.legacy_id {
+            Some(id) if !taken.contains(&id) => id,
+            _ => (FIRST_REGISTERED_ID..=monitor::http::DeviceID::MAX).find(|id| !taken.contains(id))?,
+        };
+        devices.insert(body.machine.clone(), id);
+        Some((id, true))
+    }
 }
 
 #[derive(Debug)]
@@ -45,10 +102,23 @@ async fn main() {
             .help("HTTP port to serve on")
             .default_value("7246")
         )
+        .arg(clap::Arg::with_name("categories")
+            .short("c")
+            .long("categories")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("TOML or JSON file with extra program categories")
+        )
         .get_matches();
     
     let port = args.value_of("port").unwrap().parse::<u16>().unwrap();
 
+    if let Some(path) = args.value_of("categories") {
+        let mut categories = CategoryMap::load(path).unwrap();
+        categories.extend(CategoryMap::defaults().clone());
+        *CATEGORIES.lock().unwrap() = categories;
+    }
+
     let save_file = format!("data-{}.json", chrono::Local::today().naive_local().format("%Y-%m-%d"));
     match std::fs::File::open(&save_file) {
         Ok(f) => {
@@ -59,6 +129,15 @@ async fn main() {
         }
     };
 
+    match std::fs::File::open(REGISTRY_FILE) {
+        Ok(f) => {
+            *REGISTRY.lock().unwrap() = serde_json::from_reader(f).unwrap();
+        },
+        Err(_) => {
+            eprintln!("registry file '{}' not found, creating later", REGISTRY_FILE);
+        }
+    };
+
     // save STATIC_DATA periodically
     tokio::spawn(async {
         let mut date = chrono::Local::today().naive_local();
@@ -81,17 +160,35 @@ async fn main() {
             let new_date = chrono::Local::today().naive_local();
             if date != new_date {
                 date = new_date;
-                *data = Default::default();
+                let oldest = monitor::http::unix_millis().saturating_sub(ACK_DAYS * 24 * 60 * 60 * 1000);
+                *data = data.iter().map(|(name, user)| (name.clone(), UserData {
+                    acked: user.acked.iter().filter(|(_, acked)| acked.at >= oldest).map(|(client, &acked)| (client.clone(), acked)).collect(),
+                    ..Default::default()
+                })).collect();
             }
         }
     });
     
     let api_add = warp::path!("api" / String / "add")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(body())
         .map(|name: String, body: monitor::http::Add| {
             let mut data = STATIC_DATA.lock().unwrap();
-            let data = data.entry(name).or_default();            
+            let data = data.entry(name).or_default();
+
+            // drop batches the client is resending after a lost reply
+            let acked = match (&body.client, body.seq) {
+                (Some(client), Some(seq)) => {
+                    let acked = data.acked.entry(client.clone()).or_insert(Acked { seq: 0, at: 0 });
+                    if seq <= acked.seq {
+                        return warp::reply::json(&http::AddReply { acked: Some(acked.seq) });
+                    }
+                    *acked = Acked { seq, at: monitor::http::unix_millis() };
+                    Some(seq)
+                },
+                _ => None,
+            };
+
             let data = data.monitor.entry(body.device).or_default();
 
             for (active, &secs) in &body.active {
@@ -100,15 +197,62 @@ async fn main() {
             for (open, &secs) in &body.open {
                 *data.open.entry(open.clone()).or_insert(0) += secs;
             }
+            data.idle += body.idle;
+            data.program_ids.extend(body.program_ids.clone());
+            for interval in &body.intervals {
+                http::push_interval(&mut data.intervals, interval.clone());
+            }
+            for (active, documents) in &body.documents {
+                let data = data.documents.entry(active.clone()).or_default();
+                for (document, &secs) in documents {
+                    *data.entry(document.clone()).or_insert(0) += secs;
+                }
+            }
 
             println!("req recieved");
 
-            warp::reply::json(&())
+            warp::reply::json(&http::AddReply { acked })
         });
     
-    let api_device = warp::path!("api" / String / "device")
+    let api_hello = warp::path!("api" / "hello")
         .and(warp::post())
         .and(warp::body::json())
+        .map(|body: http::Hello| {
+            if let Err(err) = body.check(http::MIN_CLIENT_VERSION) {
+                eprintln!("incompatible client: {}", err);
+            }
+            warp::reply::json(&http::Hello::new(http::MIN_CLIENT_VERSION))
+        });
+
+    let api_register = warp::path!("api" / String / "register")
+        .and(warp::post())
+        .and(body())
+        .map(|name: String, body: http::Register| {
+            let mut registry = REGISTRY.lock().unwrap();
+            let (id, new) = match registry.register(&name, &body) {
+                Some(registered) => registered,
+                None => {
+                    eprintln!("no device ids left for {}", name);
+                    let reply = warp::reply::with_status("no device ids left", warp::http::StatusCode::SERVICE_UNAVAILABLE);
+                    return Box::new(reply) as Box<dyn warp::reply::Reply>;
+                },
+            };
+
+            if new {
+                let saved = std::fs::File::create(REGISTRY_FILE)
+                    .map_err(|err| err.to_string())
+                    .and_then(|mut file| serde_json::to_writer(&mut file, &*registry).map_err(|err| err.to_string()));
+                if let Err(err) = saved {
+                    eprintln!("error saving registry: {}", err);
+                }
+            }
+
+            Box::new(warp::reply::json(&http::Registered { id }))
+        });
+
+    let api_device = warp::path!("api" / String / "device")
+        .and(warp::post())
+        .and(body())
         .map(|name: String, body: monitor::http::Device| {
             let mut data = STATIC_DATA.lock().unwrap();
             let data = data.entry(name).or_default();
@@ -147,8 +291,10 @@ async fn main() {
             ).parse::<warp::http::Uri>().unwrap())
         });
 
-    let routes = api_today
+    let routes = api_hello
+        .or(api_today)
         .or(api_add)
+        .or(api_register)
         .or(api_device)
         .or(page_device)
         .or(page_redirect)
@@ -160,6 +306,17 @@ async fn main() {
         .await;
 }
 
+/// Request body in JSON or CBOR, depending on its `Content-Type`.
+fn body<T: serde::de::DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and(warp::body::bytes())
+        .and_then(|content_type: Option<String>, bytes: warp::hyper::body::Bytes| async move {
+            http::Encoding::from_content_type(content_type.as_deref())
+                .decode(&bytes)
+                .map_err(|e| warp::reject::custom(RejectBadData(e.to_string())))
+        })
+}
+
 async fn handle_page_redirect (name: String, query: HashMap<String, String>) -> Result<impl Reply, Rejection> {
     let date_str = query.get("date").ok_or(warp::reject::not_found())?;
     let date = chrono::NaiveDate::parse_from_str(date_str, "%Y-%m-%d").map_err(|e| RejectGeneric(e.to_string()))?;
@@ -217,6 +374,7 @@ struct DataTemplate {
 
     monitor: MonitorData,
     active_data: HashMap<String, (u32, Vec<String>)>,
+    categories: Vec<(Category, u32)>,
 }
 
 async fn handle_page_device(name: String, year: u32, month: u8, day: u8, device: monitor::http::DeviceID) -> Result<Box<dyn warp::reply::Reply>, Rejection> {
@@ -230,7 +388,7 @@ async fn handle_page_device(name: String, year: u32, month: u8, day: u8, device:
             device: device,
             devices: {
                 let mut h=  HashMap::new();
-                h.insert(device, monitor::http::DeviceData { type_: Default::default(), distro: Some(device.to_string()), os: "Unknown".to_owned()});
+                h.insert(device, monitor::http::DeviceData { type_: Default::default(), distro: Some(device.to_string()), os: "Unknown".to_owned(), ..Default::default() });
                 h
             }
         }.render_string().unwrap())
@@ -271,12 +429,48 @@ async fn handle_page_device(name: String, year: u32, month: u8, day: u8, device:
     }
 
 
+    // classified now rather than when added, so the totals always match the active time
+    // and follow changes to --categories
+    let mut categories: Vec<_> = CATEGORIES.lock().unwrap().totals(&monitor.active).into_iter().collect();
+    categories.sort_by_key(|c| std::cmp::Reverse(c.1));
+
     let reply = DataTemplate {
         name,
         date,
         device,
         devices: data.devices.clone(),
-        monitor: monitor.clone(), active_data
+        monitor: monitor.clone(), active_data, categories
     }.render_string().map_err(|e| warp::reject::custom(RejectBadTemplate(e.to_string())))?;
     Ok(Box::new(warp::reply::html(reply)))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(machine: &str, legacy_id: Option<monitor::http::DeviceID>) -> http::Register {
+        http::Register { machine: machine.to_owned(), legacy_id }
+    }
+
+    #[test]
+    fn register() {
+        let mut registry = Registry::default();
+        assert_eq!(registry.register("allen", &machine("laptop", None)), Some((FIRST_REGISTERED_ID, true)));
+        assert_eq!(registry.register("allen", &machine("desktop", Some(12))), Some((12, true)));
+        // taken by the desktop, so the next free id
+        assert_eq!(registry.register("allen", &machine("tablet", Some(12))), Some((FIRST_REGISTERED_ID + 1, true)));
+        assert_eq!(registry.register("allen", &machine("laptop", Some(40))), Some((FIRST_REGISTERED_ID, false)));
+        // ids are per user
+        assert_eq!(registry.register("bob", &machine("laptop", Some(12))), Some((12, true)));
+    }
+
+    #[test]
+    fn ids_run_out() {
+        let mut registry = Registry::default();
+        let devices: HashMap<_, _> = (0..=monitor::http::DeviceID::MAX).map(|id| (id.to_string(), id)).collect();
+        registry.users.insert("allen".to_owned(), devices);
+
+        assert_eq!(registry.register("allen", &machine("new", None)), None);
+        assert_eq!(registry.register("allen", &machine("new", Some(3))), None);
+        assert_eq!(registry.register("allen", &machine("3", None)), Some((3, false)));
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+use crate::ActiveProgram;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    Development,
+    Communication,
+    Entertainment,
+    Reference,
+    Productivity,
+    System,
+    #[default]
+    Other,
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Category::Development => "Development",
+            Category::Communication => "Communication",
+            Category::Entertainment => "Entertainment",
+            Category::Reference => "Reference",
+            Category::Productivity => "Productivity",
+            Category::System => "System",
+            Category::Other => "Other",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Maps programs and subprograms to categories.
+///
+/// Keys are compared case-insensitively. A subprogram mapping takes priority over
+/// the mapping of its program; anything unmapped is `Category::Other`.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CategoryMap {
+    #[serde(default)]
+    pub programs: HashMap<String, Category>,
+    #[serde(default)]
+    pub subprograms: HashMap<String, Category>,
+}
+
+const DEFAULT_CATEGORIES: &str = include_str!("default-categories.toml");
+
+lazy_static! {
+    static ref DEFAULT: CategoryMap = CategoryMap::from_toml(DEFAULT_CATEGORIES).expect("invalid default-categories.toml");
+}
+
+impl CategoryMap {
+    /// The built-in mapping.
+    pub fn defaults() -> &'static CategoryMap {
+        &DEFAULT
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self, Box<dyn Error>> {
+        let map: CategoryMap = toml::from_str(s)?;
+        Ok(map.lowercased())
+    }
+
+    pub fn from_json(s: &str) -> Result<Self, Box<dyn Error>> {
+        let map: CategoryMap = serde_json::from_str(s)?;
+        Ok(map.lowercased())
+    }
+
+    /// Loads a mapping from a `.toml` or `.json` file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let map: CategoryMap = crate::load_file(path, "category")?;
+        Ok(map.lowercased())
+    }
+
+    fn lowercased(self) -> Self {
+        CategoryMap {
+            programs: self.programs.into_iter().map(|(k, v)| (k.to_lowercase(), v)).collect(),
+            subprograms: self.subprograms.into_iter().map(|(k, v)| (k.to_lowercase(), v)).collect(),
+        }
+    }
+
+    /// Adds the entries of `other` that aren't already mapped.
+    pub fn extend(&mut self, other: CategoryMap) {
+        for (k, v) in other.programs {
+            self.programs.entry(k).or_insert(v);
+        }
+        for (k, v) in other.subprograms {
+            self.subprograms.entry(k).or_insert(v);
+        }
+    }
+
+    pub fn classify(&self, program: &ActiveProgram) -> Category {
+        program.subprogram.as_ref()
+            .and_then(|sub| self.subprograms.get(&sub.to_lowercase()))
+            .or_else(|| self.programs.get(&program.program.to_lowercase()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sums active seconds per category.
+    pub fn totals<'a>(&self, active: impl IntoIterator<Item = (&'a ActiveProgram, &'a u32)>) -> HashMap<Category, u32> {
+        let mut totals = HashMap::new();
+        for (program, &secs) in active {
+            *totals.entry(self.classify(program)).or_insert(0) += secs;
+        }
+        totals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn active(program: &str, subprogram: Option<&str>) -> ActiveProgram {
+        ActiveProgram { program: program.to_owned(), subprogram: subprogram.map(str::to_owned) }
+    }
+
+    #[test]
+    fn fallback() {
+        let map = CategoryMap::defaults();
+        assert_eq!(map.classify(&active("Firefox", Some("youtube.com"))), Category::Entertainment);
+        assert_eq!(map.classify(&active("Firefox", Some("example.com"))), Category::Reference);
+        assert_eq!(map.classify(&active("Firefox", None)), Category::Reference);
+        assert_eq!(map.classify(&active("Code", Some("monitor-v3"))), Category::Development);
+        assert_eq!(map.classify(&active("Some Game", None)), Category::Other);
+    }
+
+    #[test]
+    fn totals() {
+        let mut map = CategoryMap::from_toml("[programs]\nFirefox = \"productivity\"").unwrap();
+        map.extend(CategoryMap::defaults().clone());
+
+        let mut active_data = HashMap::new();
+        active_data.insert(active("Firefox", None), 10);
+        active_data.insert(active("Firefox", Some("youtube.com")), 5);
+        active_data.insert(active("Code", None), 20);
+
+        let totals = map.totals(&active_data);
+        assert_eq!(totals[&Category::Productivity], 10);
+        assert_eq!(totals[&Category::Entertainment], 5);
+        assert_eq!(totals[&Category::Development], 20);
+    }
+}
@@ -1,22 +1,192 @@
 use std::{fmt, hash::Hash};
 use std::collections::HashMap;
+use std::error::Error;
 use serde::{Deserialize,Serialize};
+use serde::de::DeserializeOwned;
 
 use crate::{ActiveProgram, Program};
 
 pub type DeviceID = u16;
 
+/// Version of the protocol spoken by this crate.
+///
+/// 1. `program|subprogram` keys, only counts in `Add`
+/// 2. escaped `ActiveProgram` keys, `Hello`
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest server a client of this version works with.
+/// Older servers can't read escaped `ActiveProgram` keys.
+pub const MIN_SERVER_VERSION: u32 = 2;
+/// Oldest client a server of this version accepts.
+pub const MIN_CLIENT_VERSION: u32 = 1;
+
+/// Optional parts of the protocol, which a peer may or may not support.
+pub const FEATURES: &[&str] = &["documents", "program_ids", "intervals", "seq", "cbor", "register", "idle"];
+
+/// Body encoding of requests, selected by `Content-Type`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Json,
+    Cbor,
+}
+
+impl Encoding {
+    /// Picks the encoding for a `Content-Type` header, JSON if missing or unknown.
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        let mime = content_type.and_then(|t| t.split(';').next()).map(str::trim);
+        match mime {
+            Some(mime) if mime.eq_ignore_ascii_case("application/cbor") => Encoding::Cbor,
+            _ => Encoding::Json,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => "application/json",
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(value)?),
+            Encoding::Cbor => {
+                let mut out = Vec::new();
+                ciborium::ser::into_writer(value, &mut out)?;
+                Ok(out)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, data: &[u8]) -> Result<T, Box<dyn Error>> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(data)?),
+            Encoding::Cbor => Ok(ciborium::de::from_reader(data)?),
+        }
+    }
+}
+
+/// Sent by the client to `/api/hello`, and replied by the server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    /// Oldest version of the other side this peer works with.
+    pub min_version: u32,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    /// Hello for this crate, accepting peers from `min_version` on.
+    pub fn new(min_version: u32) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            min_version,
+            features: FEATURES.iter().map(|&f| f.to_owned()).collect(),
+        }
+    }
+
+    /// Checks whether a peer that sent this hello can talk to us, given the
+    /// oldest peer version we work with.
+    pub fn check(&self, min_version: u32) -> Result<(), String> {
+        if self.version < min_version {
+            return Err(format!("peer speaks protocol version {}, but at least {} is needed", self.version, min_version));
+        }
+        if PROTOCOL_VERSION < self.min_version {
+            return Err(format!("peer needs protocol version {} or newer, but this is version {}", self.min_version, PROTOCOL_VERSION));
+        }
+        Ok(())
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Add {
     pub device: DeviceID,
     pub active: HashMap<ActiveProgram, u32>,
     pub open: HashMap<Program, u32>,
+    /// Active seconds per document, for programs where the document is known.
+    #[serde(default)]
+    pub documents: HashMap<ActiveProgram, HashMap<String, u32>>,
+    /// Stable id of each program name used above.
+    #[serde(default)]
+    pub program_ids: HashMap<String, String>,
+    /// When each active program was focused, oldest first.
+    #[serde(default)]
+    pub intervals: Vec<Interval>,
+    /// Seconds the user was idle, which aren't counted in `active`.
+    #[serde(default)]
+    pub idle: u32,
+
+    /// Identifies one run of a client, so that `seq` can be checked.
+    #[serde(default)]
+    pub client: Option<String>,
+    /// Increases by one with every batch a client sends.
+    /// The server ignores batches whose `seq` it has already acknowledged.
+    #[serde(default)]
+    pub seq: Option<u64>,
 }
 
 impl Add {
     pub fn new(device: DeviceID) -> Self {
-        Add { device, active: HashMap::new(), open: HashMap::new() }
+        Add { device, active: HashMap::new(), open: HashMap::new(), documents: HashMap::new(), program_ids: HashMap::new(), intervals: Vec::new(), idle: 0, client: None, seq: None }
+    }
+}
+
+/// Sent to `/api/<name>/register` to get the `DeviceID` of a machine.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Register {
+    /// Stable, opaque identity of the machine.
+    pub machine: String,
+    /// ID the machine used before registering, kept if no other machine has it.
+    #[serde(default)]
+    pub legacy_id: Option<DeviceID>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Registered {
+    pub id: DeviceID,
+}
+
+/// Reply to an `Add`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AddReply {
+    /// Last `seq` applied for the client, if it sent one.
+    pub acked: Option<u64>,
+}
+
+/// A span of time during which `program` was focused.
+/// Times are milliseconds since the Unix epoch.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Interval {
+    pub program: ActiveProgram,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Largest gap between two intervals of the same program that still merges them.
+pub const INTERVAL_MERGE_GAP: u64 = 500;
+
+/// Appends `interval`, extending the last interval instead if it is the same program
+/// and ends at most `INTERVAL_MERGE_GAP` before `interval` starts.
+pub fn push_interval(intervals: &mut Vec<Interval>, interval: Interval) {
+    if let Some(last) = intervals.last_mut() {
+        if last.program == interval.program && last.end <= interval.start + INTERVAL_MERGE_GAP && interval.start <= last.end + INTERVAL_MERGE_GAP {
+            last.end = std::cmp::max(last.end, interval.end);
+            return;
+        }
     }
+    intervals.push(interval);
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
 }
 
 
@@ -26,6 +196,33 @@ pub struct DeviceData {
     pub type_: DeviceType,
     pub os: String,
     pub distro: Option<String>,
+
+    // everything below may be missing from older clients
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub kernel: Option<String>,
+    #[serde(default)]
+    pub client_version: Option<String>,
+    /// CPU model and number of logical cores, e.g. "Intel(R) Core(TM) i5-8250U CPU @ 1.60GHz (8 threads)".
+    #[serde(default)]
+    pub cpu: Option<String>,
+    /// Total memory in MiB.
+    #[serde(default)]
+    pub memory_mib: Option<u64>,
+    #[serde(default)]
+    pub monitors: Option<Vec<Monitor>>,
+    /// Desktop environment, e.g. XFCE.
+    #[serde(default)]
+    pub desktop: Option<String>,
+    #[serde(default)]
+    pub window_manager: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Monitor {
+    pub width: u32,
+    pub height: u32,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -44,8 +241,27 @@ impl Default for DeviceType {
     }
 }
 
+impl std::str::FromStr for DeviceType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "desktop" => Ok(DeviceType::Desktop),
+            "laptop" => Ok(DeviceType::Laptop),
+            "phone" => Ok(DeviceType::Phone),
+            "tablet" => Ok(DeviceType::Tablet),
+            "other" => Ok(DeviceType::Other),
+            _ => Err(format!("unknown device type: {}", s)),
+        }
+    }
+}
+
+/// Shows the hostname, or the OS and distro for clients that don't send one.
 impl fmt::Display for DeviceData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(hostname) = &self.hostname {
+            return write!(f, "{}", hostname);
+        }
         write!(f, "{}", self.os)?;
         if let Some(distro) = &self.distro {
             write!(f, ": {}", distro)?;
@@ -58,4 +274,70 @@ impl fmt::Display for DeviceData {
 pub struct Device {
     pub id: DeviceID,
     pub data: DeviceData,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interval(program: &str, start: u64, end: u64) -> Interval {
+        Interval { program: ActiveProgram { program: program.to_owned(), subprogram: None }, start, end }
+    }
+
+    #[test]
+    fn merge_intervals() {
+        let mut intervals = Vec::new();
+        push_interval(&mut intervals, interval("Code", 0, 1000));
+        push_interval(&mut intervals, interval("Code", 1020, 2000));
+        push_interval(&mut intervals, interval("Firefox", 2000, 3000));
+        push_interval(&mut intervals, interval("Code", 3000, 4000));
+        push_interval(&mut intervals, interval("Code", 9000, 10000));
+        assert_eq!(intervals, vec![
+            interval("Code", 0, 2000),
+            interval("Firefox", 2000, 3000),
+            interval("Code", 3000, 4000),
+            interval("Code", 9000, 10000),
+        ]);
+    }
+
+    #[test]
+    fn hello_check() {
+        let server = Hello::new(MIN_CLIENT_VERSION);
+        assert!(server.check(MIN_SERVER_VERSION).is_ok());
+        assert!(server.supports("seq"));
+
+        let old_server = Hello { version: 1, min_version: 1, features: Vec::new() };
+        assert!(old_server.check(MIN_SERVER_VERSION).is_err());
+        assert!(!old_server.supports("seq"));
+
+        let future_client = Hello { version: PROTOCOL_VERSION + 1, min_version: PROTOCOL_VERSION + 1, features: Vec::new() };
+        assert!(future_client.check(MIN_CLIENT_VERSION).is_err());
+    }
+
+    #[test]
+    fn encodings() {
+        let mut add = Add::new(7);
+        add.active.insert(ActiveProgram { program: "Code".to_owned(), subprogram: Some("a|b".to_owned()) }, 15);
+        add.open.insert(Program { program: "Code".to_owned() }, 15);
+        add.intervals.push(interval("Code", 0, 15000));
+
+        for &encoding in [Encoding::Json, Encoding::Cbor].iter() {
+            let data = encoding.encode(&add).unwrap();
+            let parsed: Add = encoding.decode(&data).unwrap();
+            assert_eq!(parsed.active, add.active);
+            assert_eq!(parsed.open, add.open);
+            assert_eq!(parsed.intervals, add.intervals);
+        }
+
+        assert_eq!(Encoding::from_content_type(Some("application/cbor")), Encoding::Cbor);
+        assert_eq!(Encoding::from_content_type(Some("application/json; charset=utf-8")), Encoding::Json);
+        assert_eq!(Encoding::from_content_type(None), Encoding::Json);
+    }
+
+    #[test]
+    fn add_without_intervals() {
+        let add: Add = serde_json::from_str(r#"{"device": 4, "active": {"Code": 15}, "open": {"Code": 15}}"#).unwrap();
+        assert_eq!(add.device, 4);
+        assert!(add.intervals.is_empty());
+    }
+}
@@ -1,12 +1,47 @@
+#[macro_use]
+extern crate lazy_static;
+
 use std::borrow::Cow;
-use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Visitor};
+use std::error::Error;
+use std::path::Path;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::{DeserializeOwned, Visitor}};
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+    use super::ActiveProgram;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn active_program_round_trip() {
+        let programs = [
+            ActiveProgram { program: "Code".to_owned(), subprogram: None },
+            ActiveProgram { program: "Code".to_owned(), subprogram: Some("a|b \\| c\\".to_owned()) },
+            ActiveProgram { program: "Odd|Name".to_owned(), subprogram: Some("".to_owned()) },
+        ];
+        let map: HashMap<_, _> = programs.iter().cloned().zip(0u32..).collect();
+        let json = serde_json::to_string(&map).unwrap();
+        let parsed: HashMap<ActiveProgram, u32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, map);
+    }
+
+    #[test]
+    fn active_program_v1() {
+        let parsed: HashMap<ActiveProgram, u32> = serde_json::from_str(r#"{"Firefox": 1, "Firefox|youtube.com": 2, "Code|a|b": 3}"#).unwrap();
+        assert_eq!(parsed[&ActiveProgram { program: "Firefox".to_owned(), subprogram: None }], 1);
+        assert_eq!(parsed[&ActiveProgram { program: "Firefox".to_owned(), subprogram: Some("youtube.com".to_owned()) }], 2);
+        assert_eq!(parsed[&ActiveProgram { program: "Code".to_owned(), subprogram: Some("a|b".to_owned()) }], 3);
+    }
+
+    #[test]
+    fn active_program_invalid() {
+        assert!(ActiveProgram::decode("v2:a|b|c").is_err());
+        assert!(ActiveProgram::decode("v2:a\\").is_err());
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
@@ -15,15 +50,75 @@ pub struct ActiveProgram {
     pub subprogram: Option<String>,
 }
 
+/// Prefix of the current `ActiveProgram` encoding.
+///
+/// Version 1 keys are `program` or `program|subprogram` with no escaping. Normalized
+/// program names never start with a lowercase letter, so they can't be mistaken for version 2.
+const ENCODING_V2: &str = "v2:";
+
+fn escape(s: &str, out: &mut String) {
+    for c in s.chars() {
+        if c == '\\' || c == '|' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+impl ActiveProgram {
+    /// Encodes as `v2:program|subprogram`, with `\` and `|` escaped by a backslash.
+    pub fn encode(&self) -> String {
+        let mut out = ENCODING_V2.to_owned();
+        escape(&self.program, &mut out);
+        if let Some(subprogram) = &self.subprogram {
+            out.push('|');
+            escape(subprogram, &mut out);
+        }
+        out
+    }
+
+    /// Decodes both the current and the version 1 encoding.
+    pub fn decode(s: &str) -> Result<Self, String> {
+        let s = match s.strip_prefix(ENCODING_V2) {
+            Some(s) => s,
+            None => {
+                // version 1: anything after the first bar is the subprogram
+                let mut items = s.splitn(2, '|');
+                return Ok(ActiveProgram {
+                    program: items.next().unwrap_or("").to_owned(),
+                    subprogram: items.next().map(str::to_owned),
+                });
+            }
+        };
+
+        let mut items = vec![String::new()];
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(c) => items.last_mut().unwrap().push(c),
+                    None => return Err("couldn't parse ActiveProgram: trailing backslash".to_owned()),
+                },
+                '|' => items.push(String::new()),
+                c => items.last_mut().unwrap().push(c),
+            }
+        }
+
+        let mut items = items.into_iter();
+        let program = items.next().unwrap();
+        let subprogram = items.next();
+        if items.next().is_some() {
+            return Err("couldn't parse ActiveProgram: more than one unescaped bar symbol |".to_owned());
+        }
+        Ok(ActiveProgram { program, subprogram })
+    }
+}
+
 impl Serialize for ActiveProgram {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer {
-            if let Some(subprogram) = &self.subprogram {
-                serializer.serialize_str(&format!("{}|{}", self.program, subprogram.as_str()))
-            } else {
-                serializer.serialize_str(&self.program)
-            }
+            serializer.serialize_str(&self.encode())
     }
 }
 
@@ -37,31 +132,13 @@ impl<'de> Deserialize<'de> for ActiveProgram {
             type Value = ActiveProgram;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                write!(formatter, "a string with at most one bar symbol |")
+                write!(formatter, "an encoded ActiveProgram string")
             }
 
             fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
             where
                     E: serde::de::Error, {
-                let items: Vec<_> = v.split("|").collect();
-                match items.len() {
-                    1 => {
-                        Ok(ActiveProgram {
-                            program: items[0].to_owned(),
-                            subprogram: None,
-                        })
-                    },
-                    2 => {
-                        Ok(ActiveProgram {
-                            program: items[0].to_owned(),
-                            subprogram: Some(items[1].to_owned())
-                        })
-                    },
-                    _ => {
-                        dbg!("!!");
-                        Err(E::custom("couldn't parse ActiveProgram: string contains more than one bar symbol |"))
-                    }
-                }
+                ActiveProgram::decode(v).map_err(E::custom)
             }
         }
 
@@ -75,51 +152,6 @@ pub struct Program {
     pub program: String,
 }
 
-fn predict_subprogram<'a, 'b>(program: &'a str, title: &'b str) -> Cow<'b, str> {
-    let mut browser_title = None;
-
-    let subprogram = match program.to_lowercase().as_str() {
-        "firefox" => {
-            if title.ends_with("— Mozilla Firefox") {
-                browser_title = Some(title[0..title.len()-"— Mozilla Firefox".len()].trim());
-            }
-            "".into()
-        },
-        "code" => {
-            let slices: Vec<_> = title.split(" - ").collect();
-            if slices.len() == 3 {
-                slices[1]
-            } else {
-                "".into()
-            }
-        },
-        _ => "".into()
-    };
-
-    if let Some(title) = browser_title {
-        if title == "generals.io" || title.starts_with("generals.io |") {
-            return "generals.io".into()
-        }
-        if title.ends_with("YouTube") {
-            return "youtube.com".into()
-        }
-        if title.ends_with("| Musescore.com") || title.starts_with("Musescore.com |") {
-            return "musescore.com".into()
-        }
-        if title.ends_with("- Google Docs") {
-            return "docs.google.com".into()
-        }
-        if title == "WhatsApp" {
-            return "whatsapp.com".into();
-        }
-        if title.ends_with("| Quizlet") {
-            return "quizlet.com".into();
-        }
-    }
-
-    subprogram.into()
-}
-
 fn normalize(program: &str) -> Cow<str> {
     let program = program.replace("-", " ");
 
@@ -139,16 +171,33 @@ fn normalize(program: &str) -> Cow<str> {
 pub trait RawWindowData {
     fn program(&self) -> Cow<'_, str>;
     fn title(&self) -> Cow<'_, str>;
+
+    /// Command running in the foreground of the window, for terminals.
+    /// Used as the subprogram when no rule matches.
+    fn command(&self) -> Option<Cow<'_, str>> {
+        None
+    }
+}
+
+/// A window with a fixed program and title, for tests.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TestWindow(pub &'static str, pub &'static str);
+
+#[cfg(test)]
+impl RawWindowData for TestWindow {
+    fn program(&self) -> Cow<'_, str> {
+        self.0.into()
+    }
+
+    fn title(&self) -> Cow<'_, str> {
+        self.1.into()
+    }
 }
 
 impl<T> From<T> for ActiveProgram where T: RawWindowData {
     fn from(item: T) -> Self {
-        let (program, title) = (item.program(), item.title());
-        let sub = predict_subprogram(&program, &title);
-        ActiveProgram {
-            program: normalize(&program).into_owned(),
-            subprogram: if sub.is_empty() { None } else { Some(sub.into_owned()) }
-        }
+        rules::RuleSet::defaults().classify(&item)
     }
 }
 
@@ -160,5 +209,21 @@ impl<T> From<T> for Program where T: RawWindowData {
     }
 }
 
+/// Loads a `.toml` or `.json` file. `what` names the kind of file in errors.
+pub fn load_file<T: DeserializeOwned>(path: impl AsRef<Path>, what: &str) -> Result<T, Box<dyn Error>> {
+    let path = path.as_ref();
+    let data = std::fs::read_to_string(path)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => Ok(serde_json::from_str(&data)?),
+        Some("toml") => Ok(toml::from_str(&data)?),
+        _ => Err(format!("unknown {} file type: {}", what, path.display()).into()),
+    }
+}
+
 
-pub mod http;
\ No newline at end of file
+pub mod category;
+pub mod http;
+pub mod names;
+pub mod privacy;
+pub mod rules;
+pub mod source;
\ No newline at end of file
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+
+/// Display name and stable id of an application.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct AppName {
+    /// Desktop file id (e.g. `org.gnome.Evince`), or the lowercased raw program name.
+    pub id: String,
+    pub name: String,
+}
+
+/// Resolves raw program names (WM_CLASS) to display names.
+///
+/// Installed `.desktop` entries are looked up by `StartupWMClass` and by desktop file id.
+/// The alias table is used for programs without a desktop entry, and anything else
+/// falls back to `normalize`. Lookups are case-insensitive.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AppNames {
+    #[serde(skip)]
+    desktop: HashMap<String, AppName>,
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+}
+
+/// The `[Desktop Entry]` fields we care about.
+struct DesktopEntry {
+    name: String,
+    wm_class: Option<String>,
+}
+
+fn parse_desktop_entry(data: &str) -> Option<DesktopEntry> {
+    let mut in_entry = false;
+    let mut name = None;
+    let mut wm_class = None;
+    for line in data.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_entry = line == "[Desktop Entry]";
+            continue;
+        }
+        if !in_entry {
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => continue,
+        };
+        match key {
+            "Name" => name = Some(value.to_owned()),
+            "StartupWMClass" => wm_class = Some(value.to_owned()),
+            "Hidden" if value == "true" => return None,
+            _ => {},
+        }
+    }
+    Some(DesktopEntry { name: name?, wm_class })
+}
+
+/// Directories containing `.desktop` files, most important first.
+pub fn application_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    match std::env::var_os("XDG_DATA_HOME") {
+        Some(home) => dirs.push(PathBuf::from(home)),
+        None => if let Some(home) = std::env::var_os("HOME") {
+            dirs.push(Path::new(&home).join(".local/share"));
+        },
+    }
+    let data_dirs = std::env::var("XDG_DATA_DIRS").unwrap_or_else(|_| "/usr/local/share:/usr/share".to_owned());
+    dirs.extend(data_dirs.split(':').filter(|dir| !dir.is_empty()).map(PathBuf::from));
+    dirs.into_iter().map(|dir| dir.join("applications")).collect()
+}
+
+impl AppNames {
+    /// Scans `application_dirs()` for desktop entries.
+    pub fn installed() -> Self {
+        let mut names = AppNames::default();
+        for dir in application_dirs() {
+            names.scan(&dir);
+        }
+        names
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(s)?)
+    }
+
+    /// Loads an alias table from a TOML file with an `[aliases]` section.
+    pub fn load_aliases(&mut self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let other = Self::from_toml(&fs::read_to_string(path)?)?;
+        self.aliases.extend(other.aliases);
+        Ok(())
+    }
+
+    /// Adds the desktop entries in `dir` and its subdirectories.
+    /// Entries that are already known take priority.
+    pub fn scan(&mut self, dir: &Path) {
+        self.scan_prefix(dir, "");
+    }
+
+    fn scan_prefix(&mut self, dir: &Path, prefix: &str) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        let mut entries: Vec<_> = entries.filter_map(Result::ok).map(|entry| entry.path()).collect();
+        entries.sort();
+
+        for path in entries {
+            let file_name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            if path.is_dir() {
+                self.scan_prefix(&path, &format!("{}{}-", prefix, file_name));
+                continue;
+            }
+            let id = match file_name.strip_suffix(".desktop") {
+                Some(id) => format!("{}{}", prefix, id),
+                None => continue,
+            };
+            let entry = match fs::read_to_string(&path).ok().as_deref().and_then(parse_desktop_entry) {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            let app = AppName { id: id.clone(), name: entry.name };
+            if let Some(wm_class) = entry.wm_class {
+                self.desktop.entry(wm_class.to_lowercase()).or_insert_with(|| app.clone());
+            }
+            // reverse-DNS ids like org.gnome.Evince usually have the last part as WM_CLASS
+            let short = id.rsplit('.').next().unwrap_or(&id).to_lowercase();
+            self.desktop.entry(id.to_lowercase()).or_insert_with(|| app.clone());
+            self.desktop.entry(short).or_insert(app);
+        }
+    }
+
+    pub fn resolve(&self, program: &str) -> AppName {
+        let key = program.to_lowercase();
+        if let Some(app) = self.desktop.get(&key) {
+            return app.clone();
+        }
+        let name = self.aliases.iter()
+            .find(|(alias, _)| alias.to_lowercase() == key)
+            .map(|(_, name)| name.clone())
+            .unwrap_or_else(|| crate::normalize(program).into_owned());
+        AppName { id: key, name }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn desktop_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let apps = dir.path();
+        fs::write(apps.join("libreoffice-writer.desktop"), "[Desktop Entry]\nName=LibreOffice Writer\nStartupWMClass=libreoffice-writer\n").unwrap();
+        fs::write(apps.join("org.gnome.Evince.desktop"), "[Desktop Entry]\nName=Document Viewer\n\n[Desktop Action new]\nName=New Window\n").unwrap();
+        fs::write(apps.join("code.desktop"), "[Desktop Entry]\nName=Visual Studio Code\nStartupWMClass=Code\n").unwrap();
+        fs::write(apps.join("hidden.desktop"), "[Desktop Entry]\nName=Secret Viewer\nHidden=true\n").unwrap();
+        fs::create_dir(apps.join("kde")).unwrap();
+        fs::write(apps.join("kde/okular.desktop"), "[Desktop Entry]\nName=Okular\n").unwrap();
+
+        let mut names = AppNames::default();
+        names.scan(apps);
+        names.aliases.insert("XTerm".to_owned(), "XTerm".to_owned());
+
+        assert_eq!(names.resolve("libreoffice-writer"), AppName { id: "libreoffice-writer".to_owned(), name: "LibreOffice Writer".to_owned() });
+        assert_eq!(names.resolve("Evince"), AppName { id: "org.gnome.Evince".to_owned(), name: "Document Viewer".to_owned() });
+        assert_eq!(names.resolve("code").name, "Visual Studio Code");
+        assert_eq!(names.resolve("kde-okular").name, "Okular");
+        // not found, so the normalized program name
+        assert_eq!(names.resolve("hidden").name, "Hidden");
+        assert_eq!(names.resolve("xterm"), AppName { id: "xterm".to_owned(), name: "XTerm".to_owned() });
+        assert_eq!(names.resolve("Xfce4-terminal"), AppName { id: "xfce4-terminal".to_owned(), name: "Xfce4 Terminal".to_owned() });
+    }
+}
@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::path::Path;
+use regex::Regex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::rules::{Pattern, ProgramMatch};
+use crate::{ActiveProgram, RawWindowData};
+
+/// Program name reported for windows that are hidden by `Privacy`.
+pub const PRIVATE: &str = "Private";
+
+lazy_static! {
+    /// Title markers of private browsing windows in common browsers.
+    static ref PRIVATE_WINDOW: Regex = Regex::new(
+        r"(?i)\((?:incognito|private)\)|private browsing|\[inprivate\]|private window with tor"
+    ).unwrap();
+}
+
+/// A program and/or title to hide. Both must match if both are given.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Deny {
+    #[serde(default)]
+    pub program: Option<ProgramMatch>,
+    #[serde(default)]
+    pub title: Option<Pattern>,
+}
+
+impl Deny {
+    pub fn matches(&self, program: &str, title: &str) -> bool {
+        if self.program.is_none() && self.title.is_none() {
+            return false;
+        }
+        self.program.as_ref().is_none_or(|p| p.matches(program))
+            && self.title.as_ref().is_none_or(|t| t.apply(title, "").is_some())
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Client-side redaction of window data before it is classified.
+///
+/// Denied windows and private browsing windows are reported as `PRIVATE` with
+/// no subprogram or document. If `hash_subprograms` is set, subprograms and documents
+/// are replaced by a salted hash, so they can still be told apart but not read.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Privacy {
+    #[serde(default)]
+    pub deny: Vec<Deny>,
+    #[serde(default)]
+    pub hash_subprograms: bool,
+    #[serde(default)]
+    pub salt: String,
+    #[serde(default = "default_true")]
+    pub private_windows: bool,
+}
+
+impl Default for Privacy {
+    fn default() -> Self {
+        Privacy { deny: Vec::new(), hash_subprograms: false, salt: String::new(), private_windows: true }
+    }
+}
+
+/// Window data as seen through `Privacy`.
+pub struct Redacted<'a, T: ?Sized> {
+    item: &'a T,
+    private: bool,
+}
+
+impl<'a, T: RawWindowData + ?Sized> RawWindowData for Redacted<'a, T> {
+    fn program(&self) -> Cow<'_, str> {
+        if self.private { PRIVATE.into() } else { self.item.program() }
+    }
+
+    fn title(&self) -> Cow<'_, str> {
+        if self.private { "".into() } else { self.item.title() }
+    }
+
+    fn command(&self) -> Option<Cow<'_, str>> {
+        if self.private { None } else { self.item.command() }
+    }
+}
+
+impl Privacy {
+    pub fn from_toml(s: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn from_json(s: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(s)?)
+    }
+
+    /// Loads privacy settings from a `.toml` or `.json` file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        crate::load_file(path, "privacy")
+    }
+
+    pub fn is_private(&self, program: &str, title: &str) -> bool {
+        (self.private_windows && PRIVATE_WINDOW.is_match(title))
+            || self.deny.iter().any(|deny| deny.matches(program, title))
+    }
+
+    /// Hides the window if it is private. Classify the result instead of the raw window.
+    pub fn window<'a, T: RawWindowData + ?Sized>(&self, item: &'a T) -> Redacted<'a, T> {
+        let private = self.is_private(&item.program(), &item.title());
+        Redacted { item, private }
+    }
+
+    /// Applies `hash_subprograms` to a subprogram or document.
+    pub fn detail(&self, detail: String) -> String {
+        if !self.hash_subprograms {
+            return detail;
+        }
+        let hash = Sha256::new()
+            .chain(self.salt.as_bytes())
+            .chain(detail.as_bytes())
+            .finalize();
+        hash.iter().take(6).map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Applies `hash_subprograms` to a classified program.
+    pub fn active(&self, active: ActiveProgram) -> ActiveProgram {
+        ActiveProgram {
+            program: active.program,
+            subprogram: active.subprogram.map(|sub| self.detail(sub)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::RuleSet;
+    use crate::TestWindow;
+
+    fn classify(privacy: &Privacy, program: &'static str, title: &'static str) -> ActiveProgram {
+        privacy.active(RuleSet::defaults().classify(&privacy.window(&TestWindow(program, title))))
+    }
+
+    #[test]
+    fn private_windows() {
+        let privacy = Privacy::default();
+        let private = ActiveProgram { program: PRIVATE.to_owned(), subprogram: None };
+        assert_eq!(classify(&privacy, "Google-chrome", "WhatsApp - Google Chrome (Incognito)"), private);
+        assert_eq!(classify(&privacy, "firefox", "WhatsApp — Mozilla Firefox Private Browsing"), private);
+        assert_eq!(classify(&privacy, "firefox", "WhatsApp — Mozilla Firefox").subprogram.as_deref(), Some("whatsapp.com"));
+
+        let privacy = Privacy::from_toml("private_windows = false").unwrap();
+        assert_eq!(classify(&privacy, "Google-chrome", "WhatsApp - Google Chrome (Incognito)").subprogram.as_deref(), Some("whatsapp.com"));
+    }
+
+    #[test]
+    fn deny() {
+        let privacy = Privacy::from_toml(r#"
+            [[deny]]
+            program = "keepassxc"
+
+            [[deny]]
+            program = "firefox"
+            title = { prefix = "Bank" }
+        "#).unwrap();
+        assert_eq!(classify(&privacy, "KeePassXC", "Passwords").program, PRIVATE);
+        assert_eq!(classify(&privacy, "firefox", "Bank of Examples — Mozilla Firefox").program, PRIVATE);
+        assert_eq!(classify(&privacy, "firefox", "Example Domain — Mozilla Firefox").program, "Firefox");
+    }
+
+    #[test]
+    fn hash_subprograms() {
+        let privacy = Privacy::from_toml("hash_subprograms = true\nsalt = \"abc\"").unwrap();
+        let active = classify(&privacy, "firefox", "WhatsApp — Mozilla Firefox");
+        let hashed = active.subprogram.unwrap();
+        assert_eq!(hashed.len(), 12);
+        assert_ne!(hashed, "whatsapp.com");
+        assert_eq!(privacy.detail("whatsapp.com".to_owned()), hashed);
+        assert_eq!(classify(&privacy, "firefox", "Example Domain — Mozilla Firefox").subprogram, None);
+    }
+}
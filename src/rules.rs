@@ -0,0 +1,292 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::path::Path;
+use regex::Regex;
+use serde::Deserialize;
+
+/// How a rule matches a piece of text.
+///
+/// For `prefix` and `suffix`, `$1` in the subprogram template refers to the rest of the text.
+/// For `regex`, `$1`, `$2`... and `$name` refer to capture groups.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pattern {
+    Exact(String),
+    Prefix(String),
+    Suffix(String),
+    Regex(#[serde(with = "serde_regex")] Regex),
+}
+
+mod serde_regex {
+    use regex::Regex;
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+    where
+        D: Deserializer<'de> {
+        let s = String::deserialize(deserializer)?;
+        Regex::new(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Pattern {
+    /// Matches `text` and expands `template` with the result.
+    pub fn apply(&self, text: &str, template: &str) -> Option<String> {
+        let rest = match self {
+            Pattern::Exact(s) => if text == s { "" } else { return None },
+            Pattern::Prefix(s) => text.strip_prefix(s.as_str())?,
+            Pattern::Suffix(s) => text.strip_suffix(s.as_str())?,
+            Pattern::Regex(re) => {
+                let caps = re.captures(text)?;
+                let mut out = String::new();
+                caps.expand(template, &mut out);
+                return Some(out);
+            }
+        };
+        Some(template.replace("$1", rest.trim()))
+    }
+}
+
+/// Which raw program names (e.g. WM_CLASS) a rule applies to.
+///
+/// A plain string is compared case-insensitively; patterns are matched against the lowercased name.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ProgramMatch {
+    Name(String),
+    Pattern(Pattern),
+}
+
+impl ProgramMatch {
+    pub fn matches(&self, program: &str) -> bool {
+        match self {
+            ProgramMatch::Name(name) => name.eq_ignore_ascii_case(program),
+            ProgramMatch::Pattern(pattern) => pattern.apply(&program.to_lowercase(), "").is_some(),
+        }
+    }
+}
+
+fn default_template() -> String {
+    "$1".to_owned()
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rule {
+    /// Programs this rule applies to, every program if missing.
+    #[serde(default)]
+    pub program: Option<ProgramMatch>,
+    pub title: Pattern,
+    /// Template for the resulting subprogram.
+    #[serde(default = "default_template")]
+    pub subprogram: String,
+}
+
+impl Rule {
+    pub fn apply(&self, program: &str, title: &str) -> Option<String> {
+        if let Some(p) = &self.program {
+            if !p.matches(program) {
+                return None;
+            }
+        }
+        self.title.apply(title, &self.subprogram)
+    }
+}
+
+/// Rules used to pick the subprogram of an `ActiveProgram`.
+///
+/// `browsers` extract the page title from a browser window title, which is then
+/// matched against `sites`. Windows that aren't browsers are matched against `rules`.
+/// `documents` pick the focused document out of editor and viewer titles.
+/// In every list, the first matching rule wins, and a rule producing an empty
+/// subprogram means there is none.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RuleSet {
+    #[serde(default)]
+    pub browsers: Vec<Rule>,
+    #[serde(default)]
+    pub sites: Vec<Rule>,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default)]
+    pub documents: Vec<Rule>,
+}
+
+const DEFAULT_RULES: &str = include_str!("default-rules.toml");
+
+lazy_static! {
+    static ref DEFAULT: RuleSet = RuleSet::from_toml(DEFAULT_RULES).expect("invalid default-rules.toml");
+}
+
+impl RuleSet {
+    /// The built-in rule set.
+    pub fn defaults() -> &'static RuleSet {
+        &DEFAULT
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(toml::from_str(s)?)
+    }
+
+    pub fn from_json(s: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(s)?)
+    }
+
+    /// Loads a rule set from a `.toml` or `.json` file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        crate::load_file(path, "rule")
+    }
+
+    /// Appends the rules of `other`, which then have lower priority than the rules of `self`.
+    pub fn extend(&mut self, other: RuleSet) {
+        self.browsers.extend(other.browsers);
+        self.sites.extend(other.sites);
+        self.rules.extend(other.rules);
+        self.documents.extend(other.documents);
+    }
+
+    /// Returns the subprogram for a window, if any.
+    pub fn subprogram(&self, program: &str, title: &str) -> Option<String> {
+        let page = self.browsers.iter().find_map(|rule| rule.apply(program, title));
+        let sub = match page {
+            Some(page) => self.sites.iter().find_map(|rule| rule.title.apply(&page, &rule.subprogram)),
+            None => self.rules.iter().find_map(|rule| rule.apply(program, title)),
+        };
+        sub.filter(|s| !s.is_empty())
+    }
+
+    pub fn classify<T: crate::RawWindowData + ?Sized>(&self, item: &T) -> crate::ActiveProgram {
+        let (program, title) = (item.program(), item.title());
+        crate::ActiveProgram {
+            program: crate::normalize(&program).into_owned(),
+            subprogram: self.subprogram(&program, &title)
+                .or_else(|| item.command().map(Cow::into_owned)),
+        }
+    }
+
+    /// Returns the document focused in a window, if any.
+    pub fn document<T: crate::RawWindowData + ?Sized>(&self, item: &T) -> Option<String> {
+        let (program, title) = (item.program(), item.title());
+        self.documents.iter()
+            .find_map(|rule| rule.apply(&program, &title))
+            .filter(|s| !s.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestWindow;
+
+    #[test]
+    fn default_firefox_sites() {
+        let rules = RuleSet::defaults();
+        assert_eq!(rules.subprogram("firefox", "generals.io — Mozilla Firefox").as_deref(), Some("generals.io"));
+        assert_eq!(rules.subprogram("Firefox", "generals.io | Replay — Mozilla Firefox").as_deref(), Some("generals.io"));
+        assert_eq!(rules.subprogram("firefox", "Some video - YouTube — Mozilla Firefox").as_deref(), Some("youtube.com"));
+        assert_eq!(rules.subprogram("firefox", "Notes - Google Docs — Mozilla Firefox").as_deref(), Some("docs.google.com"));
+        assert_eq!(rules.subprogram("firefox", "WhatsApp — Mozilla Firefox").as_deref(), Some("whatsapp.com"));
+        assert_eq!(rules.subprogram("firefox", "Example Domain — Mozilla Firefox"), None);
+        assert_eq!(rules.subprogram("firefox", "Mozilla Firefox"), None);
+    }
+
+    #[test]
+    fn default_chromium_sites() {
+        let rules = RuleSet::defaults();
+        let cases = [
+            ("Google-chrome", "Some video - YouTube - Google Chrome", Some("youtube.com")),
+            ("Google-chrome", "Some video - YouTube - Google Chrome (Incognito)", Some("youtube.com")),
+            ("Google-chrome", "Notes - Google Docs - Google Chrome - Work", Some("docs.google.com")),
+            ("Chromium-browser", "generals.io - Chromium", Some("generals.io")),
+            ("Brave-browser", "WhatsApp - Brave (Private)", Some("whatsapp.com")),
+            ("Vivaldi-stable", "Flashcards | Quizlet - Vivaldi", Some("quizlet.com")),
+            ("Microsoft-edge", "Some video - YouTube - Personal - Microsoft\u{200b} Edge", Some("youtube.com")),
+            ("Microsoft-edge", "Some video - YouTube and 2 more pages - Work - Microsoft Edge", Some("youtube.com")),
+            ("Microsoft-edge", "[InPrivate] WhatsApp - Microsoft Edge", Some("whatsapp.com")),
+            ("Google-chrome", "Some video - YouTube - Google Chrome - Jane-Doe (Work)", Some("youtube.com")),
+            ("Brave-browser", "WhatsApp - Brave - my-profile", Some("whatsapp.com")),
+            ("Microsoft-edge", "Some video - YouTube - Jane's Laptop - Microsoft Edge", Some("youtube.com")),
+            ("Microsoft-edge", "WhatsApp - Work-Account - Microsoft Edge", Some("whatsapp.com")),
+            ("Google-chrome", "Example Domain - Google Chrome", None),
+            ("Google-chrome", "Google Chrome", None),
+        ];
+        for &(program, title, expected) in cases.iter() {
+            assert_eq!(rules.subprogram(program, title).as_deref(), expected, "{}", title);
+        }
+    }
+
+    #[test]
+    fn default_code_workspace() {
+        let rules = RuleSet::defaults();
+        assert_eq!(rules.subprogram("code", "lib.rs - monitor-v3 - Visual Studio Code").as_deref(), Some("monitor-v3"));
+        assert_eq!(rules.subprogram("code", "Visual Studio Code"), None);
+        assert_eq!(rules.subprogram("xterm", "lib.rs - monitor-v3 - Visual Studio Code"), None);
+    }
+
+    #[test]
+    fn default_editor_projects() {
+        let rules = RuleSet::defaults();
+        let cases = [
+            ("jetbrains-idea-ce", "monitor-v3 – main.rs", Some("monitor-v3")),
+            ("jetbrains-pycharm", "scripts [~/code/scripts] – ./plot.py", Some("scripts")),
+            ("jetbrains-clion", "engine [~/code/engine] - .../src/main.cpp - CLion", Some("engine")),
+            ("jetbrains-rustrover", "monitor-v3 [~/code/monitor-v3]", Some("monitor-v3")),
+            ("jetbrains-idea", "Welcome to IntelliJ IDEA", None),
+            ("jetbrains-idea", "Settings", None),
+            ("jetbrains-pycharm", "Commit Changes", None),
+            ("jetbrains-rustrover", "monitor-v3", None),
+            ("Gvim", "main.rs (~/code/monitor-v3/src) - GVIM", Some("~/code/monitor-v3/src")),
+            ("Gvim", "main.rs + (~/code/monitor-v3/src) - GVIM1", Some("~/code/monitor-v3/src")),
+            ("Gvim", "[No Name] - GVIM", None),
+            ("nvim-qt", "lib.rs (~/code/monitor-v3/src) - NVIM", Some("~/code/monitor-v3/src")),
+            ("nvim-qt", "Neovim", None),
+            ("Emacs", "main.rs<monitor-v3> - GNU Emacs at laptop", Some("monitor-v3")),
+            ("Emacs", "*scratch* - GNU Emacs at laptop", None),
+            ("Emacs", "emacs@laptop", None),
+            ("sublime_text", "~/code/monitor-v3/src/lib.rs (monitor-v3) - Sublime Text", Some("monitor-v3")),
+            ("Sublime_text", "• lib.rs (monitor-v3) - Sublime Text (UNREGISTERED)", Some("monitor-v3")),
+            ("sublime_text", "untitled - Sublime Text", None),
+        ];
+        for &(program, title, expected) in cases.iter() {
+            assert_eq!(rules.subprogram(program, title).as_deref(), expected, "{}", title);
+        }
+    }
+
+    #[test]
+    fn default_documents() {
+        let rules = RuleSet::defaults();
+        let cases = [
+            ("code", "lib.rs - monitor-v3 - Visual Studio Code", Some("lib.rs")),
+            ("code", "● main.rs - monitor-v3 - Visual Studio Code", Some("main.rs")),
+            ("Evince", "paper.pdf", Some("paper.pdf")),
+            ("Evince", "A Paper Title — paper.pdf", Some("paper.pdf")),
+            ("okular", "paper.pdf — Okular", Some("paper.pdf")),
+            ("libreoffice-writer", "report.odt - LibreOffice Writer", Some("report.odt")),
+            ("firefox", "Example Domain — Mozilla Firefox", None),
+        ];
+        for &(program, title, expected) in cases.iter() {
+            assert_eq!(rules.document(&TestWindow(program, title)).as_deref(), expected, "{}", title);
+        }
+    }
+
+    #[test]
+    fn custom_rules() {
+        let mut rules = RuleSet::from_json(r#"{
+            "rules": [
+                { "program": "evince", "title": { "suffix": ".pdf" } },
+                { "title": { "regex": "^\\[(?P<chan>#\\w+)\\]" }, "subprogram": "irc $chan" },
+                { "program": "steam", "title": { "exact": "Steam" }, "subprogram": "library" }
+            ]
+        }"#).unwrap();
+        rules.extend(RuleSet::defaults().clone());
+
+        assert_eq!(rules.subprogram("evince", "paper.pdf").as_deref(), Some("paper"));
+        assert_eq!(rules.subprogram("hexchat", "[#rust] hexchat").as_deref(), Some("irc #rust"));
+        assert_eq!(rules.subprogram("steam", "Steam").as_deref(), Some("library"));
+        assert_eq!(rules.subprogram("firefox", "WhatsApp — Mozilla Firefox").as_deref(), Some("whatsapp.com"));
+    }
+
+    #[test]
+    fn invalid_regex() {
+        assert!(RuleSet::from_toml("[[rules]]\ntitle = { regex = \"(\" }").is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::cell::Cell;
+use std::error::Error;
+use std::time::Duration;
+
+use crate::RawWindowData;
+
+/// The windows at one point in time. Ids are whatever the source uses, e.g. X11 window ids
+/// or i3 container ids, which are pointers and so 64 bits wide.
+#[derive(Clone, Debug)]
+pub struct Windows<W> {
+    pub active: Option<u64>,
+    /// Only windows that are tracked, see `WindowSource::window_info`.
+    pub windows: Vec<(u64, W)>,
+}
+
+impl<W> Default for Windows<W> {
+    fn default() -> Self {
+        Windows { active: None, windows: Vec::new() }
+    }
+}
+
+impl<W> Windows<W> {
+    pub fn active_window(&self) -> Option<&W> {
+        let active = self.active?;
+        self.windows.iter().find(|(id, _)| *id == active).map(|(_, window)| window)
+    }
+}
+
+/// Where a client reads windows and the state of the session from.
+pub trait WindowSource {
+    type Window: RawWindowData + Clone;
+
+    fn active_window(&self) -> Result<Option<u64>, Box<dyn Error>>;
+    fn window_list(&self) -> Result<Vec<u64>, Box<dyn Error>>;
+
+    /// `None` for windows that aren't tracked, like docks, and for windows that no longer exist.
+    fn window_info(&self, id: u64) -> Result<Option<Self::Window>, Box<dyn Error>>;
+
+    /// Everything at once. Sources that can read it in one go should override this.
+    fn windows(&self) -> Result<Windows<Self::Window>, Box<dyn Error>> {
+        let active = self.active_window()?;
+        let mut windows = Vec::new();
+        for id in self.window_list()? {
+            if let Some(window) = self.window_info(id)? {
+                windows.push((id, window));
+            }
+        }
+        Ok(Windows { active, windows })
+    }
+
+    fn is_locked(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(false)
+    }
+
+    /// Time since the last keyboard or mouse input, `None` if the source can't tell.
+    fn idle_time(&self) -> Result<Option<Duration>, Box<dyn Error>> {
+        Ok(None)
+    }
+}
+
+/// One state of a `Scripted` source.
+#[derive(Clone, Debug)]
+pub struct Frame<W> {
+    pub windows: Windows<W>,
+    pub locked: bool,
+    pub idle: Duration,
+}
+
+impl<W> Frame<W> {
+    pub fn new(active: Option<u64>, windows: Vec<(u64, W)>) -> Self {
+        Frame { windows: Windows { active, windows }, locked: false, idle: Duration::from_secs(0) }
+    }
+}
+
+/// A fake source that plays back a list of states, for testing clients without a display.
+pub struct Scripted<W> {
+    frames: Vec<Frame<W>>,
+    current: Cell<usize>,
+}
+
+impl<W> Scripted<W> {
+    /// Starts at the first frame, which must exist.
+    pub fn new(frames: Vec<Frame<W>>) -> Self {
+        assert!(!frames.is_empty(), "a script needs at least one frame");
+        Scripted { frames, current: Cell::new(0) }
+    }
+
+    fn frame(&self) -> &Frame<W> {
+        &self.frames[self.current.get()]
+    }
+
+    /// Moves to the next frame. Returns false, and stays at the last frame, at the end of the script.
+    pub fn advance(&self) -> bool {
+        if self.current.get() + 1 < self.frames.len() {
+            self.current.set(self.current.get() + 1);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl<W: RawWindowData + Clone> WindowSource for Scripted<W> {
+    type Window = W;
+
+    fn active_window(&self) -> Result<Option<u64>, Box<dyn Error>> {
+        Ok(self.frame().windows.active)
+    }
+
+    fn window_list(&self) -> Result<Vec<u64>, Box<dyn Error>> {
+        Ok(self.frame().windows.windows.iter().map(|(id, _)| *id).collect())
+    }
+
+    fn window_info(&self, id: u64) -> Result<Option<W>, Box<dyn Error>> {
+        Ok(self.frame().windows.windows.iter().find(|(window, _)| *window == id).map(|(_, window)| window.clone()))
+    }
+
+    fn is_locked(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.frame().locked)
+    }
+
+    fn idle_time(&self) -> Result<Option<Duration>, Box<dyn Error>> {
+        Ok(Some(self.frame().idle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestWindow;
+
+    #[test]
+    fn scripted() {
+        let mut locked = Frame::new(None, vec![]);
+        locked.locked = true;
+        let source = Scripted::new(vec![
+            Frame::new(Some(2), vec![(1, TestWindow("code", "")), (2, TestWindow("firefox", ""))]),
+            locked,
+        ]);
+
+        let windows = source.windows().unwrap();
+        assert_eq!(windows.active_window(), Some(&TestWindow("firefox", "")));
+        assert_eq!(windows.windows.len(), 2);
+        assert_eq!(source.window_info(3).unwrap(), None);
+        assert!(!source.is_locked().unwrap());
+
+        assert!(source.advance());
+        assert!(source.is_locked().unwrap());
+        assert!(source.windows().unwrap().active_window().is_none());
+        assert!(!source.advance());
+        assert!(source.is_locked().unwrap());
+    }
+}